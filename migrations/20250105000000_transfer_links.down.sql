@@ -0,0 +1,4 @@
+DROP INDEX idx_transactions_transfer_id;
+ALTER TABLE transactions
+    DROP COLUMN counterparty_id,
+    DROP COLUMN transfer_id;
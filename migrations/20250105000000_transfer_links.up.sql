@@ -0,0 +1,11 @@
+-- Link both legs of a transfer and record the other party
+ALTER TABLE transactions
+    ADD COLUMN transfer_id UUID,
+    ADD COLUMN counterparty_id UUID REFERENCES users(id);
+
+-- Existing entries can't be paired, so each becomes its own journal
+UPDATE transactions SET transfer_id = id WHERE transfer_id IS NULL;
+
+ALTER TABLE transactions ALTER COLUMN transfer_id SET NOT NULL;
+
+CREATE INDEX idx_transactions_transfer_id ON transactions(transfer_id);
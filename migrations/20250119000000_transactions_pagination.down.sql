@@ -0,0 +1,3 @@
+CREATE INDEX idx_transactions_user_id ON transactions(user_id);
+DROP INDEX idx_transactions_user_created_at_id;
+ALTER TABLE transactions ALTER COLUMN created_at DROP NOT NULL;
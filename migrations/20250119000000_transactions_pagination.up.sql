@@ -0,0 +1,7 @@
+-- Keyset pagination on (created_at, id) needs a non-null created_at
+UPDATE transactions SET created_at = CURRENT_TIMESTAMP WHERE created_at IS NULL;
+ALTER TABLE transactions ALTER COLUMN created_at SET NOT NULL;
+
+-- Serves GET /transactions ordered by (created_at, id) for one user
+CREATE INDEX idx_transactions_user_created_at_id ON transactions(user_id, created_at DESC, id DESC);
+DROP INDEX idx_transactions_user_id;
@@ -0,0 +1,4 @@
+ALTER TABLE transactions
+    DROP COLUMN updated_at,
+    DROP COLUMN memo,
+    DROP COLUMN balance_after;
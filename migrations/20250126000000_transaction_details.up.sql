@@ -0,0 +1,7 @@
+-- Per-entry details shown by GET /transactions/{id}
+ALTER TABLE transactions
+    ADD COLUMN balance_after DECIMAL(19,2),
+    ADD COLUMN memo TEXT,
+    ADD COLUMN updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+UPDATE transactions SET updated_at = created_at;
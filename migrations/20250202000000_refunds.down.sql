@@ -0,0 +1,20 @@
+DELETE FROM transactions WHERE transaction_type IN ('REFUND_SENT', 'REFUND_RECEIVED');
+UPDATE transactions SET status = 'SUCCESS' WHERE status = 'REVERSED';
+
+DROP INDEX idx_transactions_refund_of;
+ALTER TABLE transactions DROP COLUMN refund_of;
+
+-- Postgres can't drop enum values, so recreate both types
+ALTER TYPE transaction_type RENAME TO transaction_type_old;
+CREATE TYPE transaction_type AS ENUM ('SENT', 'RECEIVED');
+ALTER TABLE transactions
+    ALTER COLUMN transaction_type TYPE transaction_type
+    USING transaction_type::text::transaction_type;
+DROP TYPE transaction_type_old;
+
+ALTER TYPE transaction_status RENAME TO transaction_status_old;
+CREATE TYPE transaction_status AS ENUM ('SUCCESS', 'FAILURE');
+ALTER TABLE transactions
+    ALTER COLUMN status TYPE transaction_status
+    USING status::text::transaction_status;
+DROP TYPE transaction_status_old;
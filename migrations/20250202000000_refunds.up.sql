@@ -0,0 +1,9 @@
+-- Compensating entries for refunds of completed transfers
+ALTER TYPE transaction_type ADD VALUE 'REFUND_SENT';
+ALTER TYPE transaction_type ADD VALUE 'REFUND_RECEIVED';
+ALTER TYPE transaction_status ADD VALUE 'REVERSED';
+
+-- Refund entries point at the transfer_id they pay back
+ALTER TABLE transactions ADD COLUMN refund_of UUID;
+
+CREATE INDEX idx_transactions_refund_of ON transactions(refund_of);
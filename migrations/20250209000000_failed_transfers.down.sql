@@ -0,0 +1,17 @@
+CREATE OR REPLACE FUNCTION notify_transaction_insert()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'transaction_insert',
+        json_build_object(
+            'transaction_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DELETE FROM transactions WHERE status = 'FAILURE';
+ALTER TABLE transactions DROP COLUMN failure_reason;
@@ -0,0 +1,19 @@
+-- Machine readable reason for FAILURE entries, e.g. INSUFFICIENT_BALANCE
+ALTER TABLE transactions ADD COLUMN failure_reason VARCHAR(64);
+
+-- Include the failure reason in webhook notifications
+CREATE OR REPLACE FUNCTION notify_transaction_insert()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'transaction_insert',
+        json_build_object(
+            'transaction_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount,
+            'failure_reason', NEW.failure_reason
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,26 @@
+CREATE OR REPLACE FUNCTION notify_transaction_insert()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'transaction_insert',
+        json_build_object(
+            'transaction_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount,
+            'failure_reason', NEW.failure_reason
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE transactions DROP COLUMN currency;
+
+-- Only the USD wallet survives the downgrade
+ALTER TABLE users ADD COLUMN balance DECIMAL(19,2) NOT NULL DEFAULT 0;
+UPDATE users SET balance = wallets.balance
+FROM wallets
+WHERE wallets.user_id = users.id AND wallets.currency = 'USD';
+ALTER TABLE users ALTER COLUMN balance DROP DEFAULT;
+
+DROP TABLE wallets;
@@ -0,0 +1,37 @@
+-- One wallet per user and currency replaces users.balance
+CREATE TABLE wallets (
+    user_id UUID NOT NULL,
+    currency CHAR(3) NOT NULL,
+    balance DECIMAL(19,2) NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, currency),
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    CHECK (balance >= 0)
+);
+
+-- Existing balances had no currency, they become USD wallets
+INSERT INTO wallets (user_id, currency, balance)
+SELECT id, 'USD', balance FROM users;
+
+ALTER TABLE users DROP COLUMN balance;
+
+ALTER TABLE transactions ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'USD';
+ALTER TABLE transactions ALTER COLUMN currency DROP DEFAULT;
+
+-- Include the currency in webhook notifications
+CREATE OR REPLACE FUNCTION notify_transaction_insert()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'transaction_insert',
+        json_build_object(
+            'transaction_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount,
+            'currency', NEW.currency,
+            'failure_reason', NEW.failure_reason
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
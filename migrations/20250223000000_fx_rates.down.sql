@@ -0,0 +1,4 @@
+ALTER TABLE transactions DROP COLUMN fx_quote_id;
+DROP TABLE fx_quotes;
+DROP TABLE fx_rates;
+ALTER TABLE users DROP COLUMN is_admin;
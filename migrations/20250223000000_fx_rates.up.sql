@@ -0,0 +1,28 @@
+-- Admins manage rates and other users' accounts
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- 1 base_currency = rate quote_currency
+CREATE TABLE fx_rates (
+    base_currency CHAR(3) NOT NULL,
+    quote_currency CHAR(3) NOT NULL,
+    rate DECIMAL(19,8) NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (base_currency, quote_currency),
+    CHECK (rate > 0)
+);
+
+-- A rate locked for one conversion until it expires
+CREATE TABLE fx_quotes (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    from_currency CHAR(3) NOT NULL,
+    to_currency CHAR(3) NOT NULL,
+    rate DECIMAL(19,8) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- Conversion transfers record the quote they used
+ALTER TABLE transactions ADD COLUMN fx_quote_id UUID REFERENCES fx_quotes(id);
@@ -0,0 +1,2 @@
+DROP TABLE scheduled_transfers;
+DROP TYPE scheduled_transfer_status;
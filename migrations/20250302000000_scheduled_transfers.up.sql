@@ -0,0 +1,25 @@
+CREATE TYPE scheduled_transfer_status AS ENUM ('PENDING', 'SUCCESS', 'FAILURE', 'CANCELLED');
+
+-- Transfers created now and executed by the scheduler at execute_at
+CREATE TABLE scheduled_transfers (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    receiver_email VARCHAR(255) NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    memo TEXT,
+    execute_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    status scheduled_transfer_status NOT NULL DEFAULT 'PENDING',
+    transfer_id UUID,
+    failure_reason TEXT,
+    executed_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    CHECK (amount > 0)
+);
+
+-- The scheduler only looks at pending transfers that are due
+CREATE INDEX idx_scheduled_transfers_pending ON scheduled_transfers(execute_at)
+    WHERE status = 'PENDING';
+
+CREATE INDEX idx_scheduled_transfers_user_id ON scheduled_transfers(user_id, execute_at);
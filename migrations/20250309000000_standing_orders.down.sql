@@ -0,0 +1,5 @@
+DROP TABLE standing_order_runs;
+DROP FUNCTION standing_order_run_at;
+DROP TABLE standing_orders;
+DROP TYPE standing_order_interval;
+DROP TYPE standing_order_status;
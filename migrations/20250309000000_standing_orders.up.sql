@@ -0,0 +1,58 @@
+CREATE TYPE standing_order_status AS ENUM ('ACTIVE', 'PAUSED', 'CANCELLED', 'COMPLETED');
+CREATE TYPE standing_order_interval AS ENUM ('DAY', 'WEEK', 'MONTH');
+
+-- Recurring transfer, repeated every interval_count interval_units from start_at until end_at
+CREATE TABLE standing_orders (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    receiver_email VARCHAR(255) NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    memo TEXT,
+    interval_unit standing_order_interval NOT NULL,
+    interval_count INTEGER NOT NULL DEFAULT 1,
+    start_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    end_at TIMESTAMP WITH TIME ZONE,
+    -- Occurrences are numbered from 0, next_run_at is the time of next_occurrence
+    next_occurrence INTEGER NOT NULL DEFAULT 0,
+    next_run_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    status standing_order_status NOT NULL DEFAULT 'ACTIVE',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    CHECK (amount > 0),
+    CHECK (interval_count > 0)
+);
+
+CREATE INDEX idx_standing_orders_active ON standing_orders(next_run_at)
+    WHERE status = 'ACTIVE';
+
+CREATE INDEX idx_standing_orders_user_id ON standing_orders(user_id, created_at);
+
+-- Time of an occurrence, always counted from start_at so monthly orders don't drift
+CREATE FUNCTION standing_order_run_at(
+    start_at TIMESTAMP WITH TIME ZONE,
+    interval_unit standing_order_interval,
+    interval_count INTEGER,
+    occurrence INTEGER
+) RETURNS TIMESTAMP WITH TIME ZONE AS $$
+    SELECT start_at + CASE interval_unit
+        WHEN 'DAY' THEN make_interval(days => interval_count * occurrence)
+        WHEN 'WEEK' THEN make_interval(weeks => interval_count * occurrence)
+        WHEN 'MONTH' THEN make_interval(months => interval_count * occurrence)
+    END
+$$ LANGUAGE SQL STABLE;
+
+-- One entry per executed occurrence
+CREATE TABLE standing_order_runs (
+    id UUID PRIMARY KEY,
+    standing_order_id UUID NOT NULL,
+    occurrence INTEGER NOT NULL,
+    scheduled_for TIMESTAMP WITH TIME ZONE NOT NULL,
+    status transaction_status NOT NULL,
+    transfer_id UUID,
+    failure_reason TEXT,
+    executed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (standing_order_id) REFERENCES standing_orders(id),
+    UNIQUE (standing_order_id, occurrence)
+);
@@ -0,0 +1,5 @@
+DROP TRIGGER payment_request_update_trigger ON payment_requests;
+DROP TRIGGER payment_request_insert_trigger ON payment_requests;
+DROP FUNCTION notify_payment_request_update;
+DROP TABLE payment_requests;
+DROP TYPE payment_request_status;
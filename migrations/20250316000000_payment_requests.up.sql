@@ -0,0 +1,55 @@
+CREATE TYPE payment_request_status AS ENUM ('PENDING', 'PAID', 'DECLINED', 'EXPIRED', 'CANCELLED');
+
+-- A request from requester_id for payer_id to send them money
+CREATE TABLE payment_requests (
+    id UUID PRIMARY KEY,
+    requester_id UUID NOT NULL,
+    payer_id UUID NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    note TEXT,
+    status payment_request_status NOT NULL DEFAULT 'PENDING',
+    -- Set once the request is paid
+    transfer_id UUID,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (requester_id) REFERENCES users(id),
+    FOREIGN KEY (payer_id) REFERENCES users(id),
+    CHECK (amount > 0),
+    CHECK (requester_id <> payer_id)
+);
+
+CREATE INDEX idx_payment_requests_payer_id ON payment_requests(payer_id, created_at);
+CREATE INDEX idx_payment_requests_requester_id ON payment_requests(requester_id, created_at);
+CREATE INDEX idx_payment_requests_pending ON payment_requests(expires_at)
+    WHERE status = 'PENDING';
+
+-- Every new request and status change goes to the webhook pipeline
+CREATE FUNCTION notify_payment_request_update()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'payment_request_update',
+        json_build_object(
+            'payment_request_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount,
+            'currency', NEW.currency,
+            'transfer_id', NEW.transfer_id
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER payment_request_insert_trigger
+    AFTER INSERT ON payment_requests
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_payment_request_update();
+
+CREATE TRIGGER payment_request_update_trigger
+    AFTER UPDATE OF status ON payment_requests
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION notify_payment_request_update();
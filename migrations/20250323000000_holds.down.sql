@@ -0,0 +1,4 @@
+DROP TABLE holds;
+DROP TYPE hold_status;
+ALTER TABLE wallets DROP CONSTRAINT wallets_held_check;
+ALTER TABLE wallets DROP COLUMN held;
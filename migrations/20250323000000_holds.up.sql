@@ -0,0 +1,30 @@
+-- Funds reserved by active holds, available balance is balance - held
+ALTER TABLE wallets ADD COLUMN held DECIMAL(19,2) NOT NULL DEFAULT 0;
+ALTER TABLE wallets ADD CONSTRAINT wallets_held_check CHECK (held >= 0 AND held <= balance);
+
+CREATE TYPE hold_status AS ENUM ('ACTIVE', 'CAPTURED', 'VOIDED', 'EXPIRED');
+
+-- An authorization by user_id for merchant_id to take up to amount
+CREATE TABLE holds (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    merchant_id UUID NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    memo TEXT,
+    status hold_status NOT NULL DEFAULT 'ACTIVE',
+    captured_amount DECIMAL(19,2),
+    -- Set once the hold is captured
+    transfer_id UUID,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (merchant_id) REFERENCES users(id),
+    CHECK (amount > 0),
+    CHECK (captured_amount > 0 AND captured_amount <= amount)
+);
+
+CREATE INDEX idx_holds_user_id ON holds(user_id, created_at);
+CREATE INDEX idx_holds_merchant_id ON holds(merchant_id, created_at);
+CREATE INDEX idx_holds_active ON holds(expires_at) WHERE status = 'ACTIVE';
@@ -0,0 +1,3 @@
+ALTER TABLE transactions ALTER COLUMN balance_after DROP NOT NULL;
+DROP INDEX idx_transactions_user_currency_ledger_seq;
+ALTER TABLE transactions DROP COLUMN ledger_seq;
@@ -0,0 +1,36 @@
+-- Order in which entries hit their wallet. created_at is the start of the database
+-- transaction, so concurrent transfers can tie or commit out of created_at order
+CREATE SEQUENCE transactions_ledger_seq;
+ALTER TABLE transactions ADD COLUMN ledger_seq BIGINT;
+
+UPDATE transactions t
+SET ledger_seq = o.ledger_seq
+FROM (SELECT id, ROW_NUMBER() OVER (ORDER BY created_at, id) AS ledger_seq FROM transactions) o
+WHERE t.id = o.id;
+
+SELECT setval('transactions_ledger_seq', COALESCE(MAX(ledger_seq), 0) + 1, false) FROM transactions;
+
+ALTER TABLE transactions
+    ALTER COLUMN ledger_seq SET DEFAULT nextval('transactions_ledger_seq'),
+    ALTER COLUMN ledger_seq SET NOT NULL;
+ALTER SEQUENCE transactions_ledger_seq OWNED BY transactions.ledger_seq;
+
+CREATE INDEX idx_transactions_user_currency_ledger_seq
+    ON transactions(user_id, currency, ledger_seq);
+
+-- Backfill the running balance of entries written before balance_after was kept,
+-- declined entries carry the balance they left unchanged
+UPDATE transactions t
+SET balance_after = r.running_balance
+FROM (
+    SELECT id,
+        SUM(CASE
+            WHEN status = 'FAILURE' THEN 0
+            WHEN transaction_type IN ('SENT', 'REFUND_SENT') THEN -amount
+            ELSE amount
+        END) OVER (PARTITION BY user_id, currency ORDER BY ledger_seq) AS running_balance
+    FROM transactions
+) r
+WHERE t.id = r.id AND t.balance_after IS NULL;
+
+ALTER TABLE transactions ALTER COLUMN balance_after SET NOT NULL;
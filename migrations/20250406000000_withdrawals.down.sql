@@ -0,0 +1,12 @@
+DROP TABLE withdrawals;
+DROP TYPE withdrawal_status;
+
+DELETE FROM transactions WHERE transaction_type IN ('WITHDRAWAL', 'WITHDRAWAL_REFUND');
+
+-- Postgres can't drop enum values, so recreate the type
+ALTER TYPE transaction_type RENAME TO transaction_type_old;
+CREATE TYPE transaction_type AS ENUM ('SENT', 'RECEIVED', 'REFUND_SENT', 'REFUND_RECEIVED');
+ALTER TABLE transactions
+    ALTER COLUMN transaction_type TYPE transaction_type
+    USING transaction_type::text::transaction_type;
+DROP TYPE transaction_type_old;
@@ -0,0 +1,24 @@
+-- Money leaving to an external bank account, and coming back when the bank rejects it
+ALTER TYPE transaction_type ADD VALUE 'WITHDRAWAL';
+ALTER TYPE transaction_type ADD VALUE 'WITHDRAWAL_REFUND';
+
+CREATE TYPE withdrawal_status AS ENUM ('PENDING', 'SUCCESS', 'FAILURE');
+
+-- Funds leave the wallet when the withdrawal is created, a FAILURE puts them back
+CREATE TABLE withdrawals (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    bank_account TEXT NOT NULL,
+    status withdrawal_status NOT NULL DEFAULT 'PENDING',
+    -- The connector's own id for the payout, once it accepted it
+    connector_reference TEXT,
+    failure_reason TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    CHECK (amount > 0)
+);
+
+CREATE INDEX idx_withdrawals_user_id ON withdrawals(user_id, created_at);
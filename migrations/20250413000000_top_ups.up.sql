@@ -0,0 +1,24 @@
+-- Money coming in from a card or bank account through the funding provider
+ALTER TYPE transaction_type ADD VALUE 'TOP_UP';
+
+CREATE TYPE top_up_status AS ENUM ('PENDING', 'SUCCESS', 'FAILURE');
+
+-- The wallet is only credited once the provider confirms the charge
+CREATE TABLE top_ups (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    amount DECIMAL(19,2) NOT NULL,
+    currency CHAR(3) NOT NULL,
+    -- Card token or account the provider charges
+    source TEXT NOT NULL,
+    status top_up_status NOT NULL DEFAULT 'PENDING',
+    -- The provider's own id for the charge, once it accepted it
+    provider_reference TEXT,
+    failure_reason TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    CHECK (amount > 0)
+);
+
+CREATE INDEX idx_top_ups_user_id ON top_ups(user_id, created_at);
@@ -0,0 +1,25 @@
+-- Free-form key/value pairs from the sender, e.g. an order id, kept on both legs
+ALTER TABLE transactions ADD COLUMN metadata JSONB;
+
+-- Lets merchants find entries by metadata key
+CREATE INDEX idx_transactions_metadata ON transactions USING GIN (metadata);
+
+-- Include the memo and metadata in webhook notifications
+CREATE OR REPLACE FUNCTION notify_transaction_insert()
+RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'transaction_insert',
+        json_build_object(
+            'transaction_id', NEW.id,
+            'status', NEW.status,
+            'amount', NEW.amount,
+            'currency', NEW.currency,
+            'failure_reason', NEW.failure_reason,
+            'memo', NEW.memo,
+            'metadata', NEW.metadata
+        )::text
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
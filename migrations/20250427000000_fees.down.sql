@@ -0,0 +1,18 @@
+DROP TABLE fee_rules;
+
+DELETE FROM transactions WHERE transaction_type IN ('FEE', 'FEE_INCOME');
+DELETE FROM wallets WHERE user_id = '00000000-0000-0000-0000-000000000fee';
+DELETE FROM users WHERE id = '00000000-0000-0000-0000-000000000fee';
+
+ALTER TABLE users DROP COLUMN tier;
+
+-- Postgres can't drop enum values, so recreate the type
+ALTER TYPE transaction_type RENAME TO transaction_type_old;
+CREATE TYPE transaction_type AS ENUM (
+    'SENT', 'RECEIVED', 'REFUND_SENT', 'REFUND_RECEIVED', 'WITHDRAWAL', 'WITHDRAWAL_REFUND',
+    'TOP_UP'
+);
+ALTER TABLE transactions
+    ALTER COLUMN transaction_type TYPE transaction_type
+    USING transaction_type::text::transaction_type;
+DROP TYPE transaction_type_old;
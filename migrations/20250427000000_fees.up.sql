@@ -0,0 +1,33 @@
+-- The fee a sender pays on top of a transfer, and the same amount arriving in the house account
+ALTER TYPE transaction_type ADD VALUE 'FEE';
+ALTER TYPE transaction_type ADD VALUE 'FEE_INCOME';
+
+-- Pricing tier, fee schedules can target a tier
+ALTER TABLE users ADD COLUMN tier VARCHAR(32) NOT NULL DEFAULT 'STANDARD';
+
+-- House account collecting fees. It can't log in, its password hash matches nothing
+INSERT INTO users (id, email, password_hash)
+VALUES ('00000000-0000-0000-0000-000000000fee', 'fees@house.local', '!');
+
+-- One band of a fee schedule. A schedule is every rule with the same transaction_type,
+-- user_tier and currency, NULL matching any. The band with the highest min_amount at or
+-- below the amount prices it: flat_fee + percentage of the amount, kept within min_fee
+-- and max_fee
+CREATE TABLE fee_rules (
+    id UUID PRIMARY KEY,
+    transaction_type VARCHAR(32),
+    user_tier VARCHAR(32),
+    currency CHAR(3) NOT NULL,
+    min_amount DECIMAL(19,2) NOT NULL DEFAULT 0,
+    flat_fee DECIMAL(19,2) NOT NULL DEFAULT 0,
+    percentage DECIMAL(7,4) NOT NULL DEFAULT 0,
+    min_fee DECIMAL(19,2),
+    max_fee DECIMAL(19,2),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK (min_amount >= 0 AND flat_fee >= 0 AND percentage >= 0 AND percentage <= 100),
+    CHECK (min_fee IS NULL OR max_fee IS NULL OR min_fee <= max_fee)
+);
+
+CREATE UNIQUE INDEX idx_fee_rules_band ON fee_rules(
+    COALESCE(transaction_type, ''), COALESCE(user_tier, ''), currency, min_amount
+);
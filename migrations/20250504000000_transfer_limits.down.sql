@@ -0,0 +1,3 @@
+DROP INDEX idx_transactions_sent_velocity;
+DROP TABLE user_limits;
+DROP TABLE tier_limits;
@@ -0,0 +1,30 @@
+-- Caps on what a user sends out of each currency wallet. NULL means no cap
+CREATE TABLE tier_limits (
+    tier VARCHAR(32) PRIMARY KEY,
+    per_transaction_amount DECIMAL(19,2),
+    daily_amount DECIMAL(19,2),
+    monthly_amount DECIMAL(19,2),
+    daily_count INTEGER,
+    monthly_count INTEGER,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO tier_limits
+    (tier, per_transaction_amount, daily_amount, monthly_amount, daily_count, monthly_count)
+VALUES ('STANDARD', 10000, 25000, 100000, 100, 1000);
+
+-- Set by an admin, replaces the user's tier limits as a whole
+CREATE TABLE user_limits (
+    user_id UUID PRIMARY KEY,
+    per_transaction_amount DECIMAL(19,2),
+    daily_amount DECIMAL(19,2),
+    monthly_amount DECIMAL(19,2),
+    daily_count INTEGER,
+    monthly_count INTEGER,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- Sums a sender's recent transfers per currency
+CREATE INDEX idx_transactions_sent_velocity ON transactions(user_id, currency, created_at)
+    WHERE transaction_type = 'SENT' AND status <> 'FAILURE';
@@ -0,0 +1,29 @@
+-- Refresh tokens are only stored as their SHA-256. A login starts a family, every refresh
+-- uses up the family's current token and adds the next one
+CREATE TABLE refresh_tokens (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    family_id UUID NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- The access token issued along with it, revoked with the family
+    access_jti UUID NOT NULL,
+    access_expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- Set once it was exchanged, using it again revokes the whole family
+    used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX idx_refresh_tokens_user_id ON refresh_tokens(user_id);
+CREATE INDEX idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+CREATE INDEX idx_refresh_tokens_access_jti ON refresh_tokens(access_jti);
+
+-- Access tokens that were revoked before they expired, checked on every request
+CREATE TABLE revoked_tokens (
+    jti UUID PRIMARY KEY,
+    -- The token is rejected anyway after this, so the entry can be dropped
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE users SET is_admin = TRUE WHERE role = 'ADMIN';
+ALTER TABLE users DROP COLUMN role;
+DROP TYPE user_role;
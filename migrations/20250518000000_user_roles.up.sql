@@ -0,0 +1,7 @@
+-- What a user may do, carried in their access tokens. Admins may do everything, support
+-- staff can also read the admin views and merchants can also use the merchant API
+CREATE TYPE user_role AS ENUM ('USER', 'MERCHANT', 'SUPPORT', 'ADMIN');
+
+ALTER TABLE users ADD COLUMN role user_role NOT NULL DEFAULT 'USER';
+UPDATE users SET role = 'ADMIN' WHERE is_admin;
+ALTER TABLE users DROP COLUMN is_admin;
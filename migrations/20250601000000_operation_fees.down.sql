@@ -0,0 +1,13 @@
+-- Active holds go back to reserving only their amount
+UPDATE wallets w
+SET held = w.held - h.fee
+FROM (
+    SELECT user_id, currency, SUM(fee) as fee
+    FROM holds
+    WHERE status = 'ACTIVE'
+    GROUP BY user_id, currency
+) h
+WHERE w.user_id = h.user_id AND w.currency = h.currency;
+
+ALTER TABLE withdrawals DROP COLUMN fee;
+ALTER TABLE holds DROP COLUMN fee;
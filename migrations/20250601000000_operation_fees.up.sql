@@ -0,0 +1,4 @@
+-- Fees reserved with a hold, so capturing it can always pay them, and fees charged on
+-- withdrawals
+ALTER TABLE holds ADD COLUMN fee DECIMAL(19,2) NOT NULL DEFAULT 0;
+ALTER TABLE withdrawals ADD COLUMN fee DECIMAL(19,2) NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Runs that failed on a database error are retried after retry_at, until the worker gives
+-- up after a few attempts
+ALTER TABLE scheduled_transfers ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE scheduled_transfers ADD COLUMN retry_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,4 @@
+DROP FUNCTION standing_order_next_occurrence;
+
+ALTER TABLE standing_orders DROP COLUMN retry_at;
+ALTER TABLE standing_orders DROP COLUMN attempts;
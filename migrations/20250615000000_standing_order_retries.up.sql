@@ -0,0 +1,20 @@
+-- Runs that failed on a database error are retried after retry_at. After a few attempts
+-- the occurrence is recorded as failed and the order moves on
+ALTER TABLE standing_orders ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE standing_orders ADD COLUMN retry_at TIMESTAMP WITH TIME ZONE;
+
+-- First occurrence from from_occurrence on that runs after `after`. Both the worker and
+-- resume skip occurrences whose time has passed
+CREATE FUNCTION standing_order_next_occurrence(
+    start_at TIMESTAMP WITH TIME ZONE,
+    interval_unit standing_order_interval,
+    interval_count INTEGER,
+    from_occurrence INTEGER,
+    after TIMESTAMP WITH TIME ZONE
+) RETURNS INTEGER AS $$
+    SELECT occurrence
+    FROM generate_series(from_occurrence, from_occurrence + 100000) AS occurrence
+    WHERE standing_order_run_at(start_at, interval_unit, interval_count, occurrence) > after
+    ORDER BY occurrence
+    LIMIT 1
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,3 @@
+DROP INDEX idx_withdrawals_retry_at;
+ALTER TABLE withdrawals DROP COLUMN retry_at;
+ALTER TABLE withdrawals DROP COLUMN attempts;
@@ -0,0 +1,7 @@
+-- Withdrawals the bank hasn't answered for are handed to it again once retry_at passes,
+-- it is cleared once the bank accepted or rejected the withdrawal
+ALTER TABLE withdrawals ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE withdrawals ADD COLUMN retry_at TIMESTAMP WITH TIME ZONE;
+UPDATE withdrawals SET retry_at = NOW() WHERE status = 'PENDING' AND connector_reference IS NULL;
+
+CREATE INDEX idx_withdrawals_retry_at ON withdrawals(retry_at) WHERE retry_at IS NOT NULL;
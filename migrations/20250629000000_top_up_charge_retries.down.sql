@@ -0,0 +1,3 @@
+DROP INDEX idx_top_ups_retry_at;
+ALTER TABLE top_ups DROP COLUMN retry_at;
+ALTER TABLE top_ups DROP COLUMN attempts;
@@ -0,0 +1,8 @@
+-- Top-ups the provider hasn't answered for, or whose captured charge wasn't credited yet,
+-- are charged again once retry_at passes. It is cleared once the charge is settled or
+-- waits for the provider's callback
+ALTER TABLE top_ups ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE top_ups ADD COLUMN retry_at TIMESTAMP WITH TIME ZONE;
+UPDATE top_ups SET retry_at = NOW() WHERE status = 'PENDING' AND provider_reference IS NULL;
+
+CREATE INDEX idx_top_ups_retry_at ON top_ups(retry_at) WHERE retry_at IS NOT NULL;
@@ -0,0 +1,3 @@
+-- When a reserved key's response was last waited for. A reservation nobody answered within
+-- the lease, because the process died or the response couldn't be stored, can be taken over
+ALTER TABLE idempotency_keys ADD COLUMN locked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP;
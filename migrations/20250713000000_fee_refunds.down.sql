@@ -0,0 +1,16 @@
+DELETE FROM transactions WHERE transaction_type IN ('FEE_REFUND', 'FEE_REFUND_SENT');
+
+-- Postgres can't drop enum values, so recreate the type. The velocity index compares
+-- against the type, so it is rebuilt with it
+DROP INDEX idx_transactions_sent_velocity;
+ALTER TYPE transaction_type RENAME TO transaction_type_old;
+CREATE TYPE transaction_type AS ENUM (
+    'SENT', 'RECEIVED', 'REFUND_SENT', 'REFUND_RECEIVED', 'WITHDRAWAL', 'WITHDRAWAL_REFUND',
+    'TOP_UP', 'FEE', 'FEE_INCOME'
+);
+ALTER TABLE transactions
+    ALTER COLUMN transaction_type TYPE transaction_type
+    USING transaction_type::text::transaction_type;
+DROP TYPE transaction_type_old;
+CREATE INDEX idx_transactions_sent_velocity ON transactions(user_id, currency, created_at)
+    WHERE transaction_type = 'SENT' AND status <> 'FAILURE';
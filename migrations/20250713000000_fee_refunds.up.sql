@@ -0,0 +1,4 @@
+-- A fee given back to its payer out of the house account, when the operation it paid
+-- for never went through
+ALTER TYPE transaction_type ADD VALUE 'FEE_REFUND';
+ALTER TYPE transaction_type ADD VALUE 'FEE_REFUND_SENT';
@@ -0,0 +1,110 @@
+pub mod simulated;
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// What a bank connector needs to pay a withdrawal out
+pub struct WithdrawalOrder {
+    pub id: Uuid,
+    pub amount: Decimal,
+    pub currency: String,
+    pub bank_account: String,
+}
+
+// What a funding provider needs to charge a top-up
+pub struct TopUpOrder {
+    pub id: Uuid,
+    pub amount: Decimal,
+    pub currency: String,
+    pub source: String,
+}
+
+// How an external rail finished a payment it accepted
+pub enum SettlementOutcome {
+    Completed,
+    Failed(String),
+}
+
+pub struct WithdrawalCallback {
+    pub withdrawal_id: Uuid,
+    pub outcome: SettlementOutcome,
+}
+
+pub struct TopUpCallback {
+    pub top_up_id: Uuid,
+    pub outcome: SettlementOutcome,
+}
+
+// How a provider answered a charge, accepted ones come with the provider's reference
+pub enum Charge {
+    // The funds are confirmed already
+    Captured(String),
+    // The provider confirms or declines later through its callback
+    Pending(String),
+    // Declined, nothing is charged
+    Declined(String),
+}
+
+// How a bank answered a withdrawal handed to it
+pub enum Submission {
+    // Accepted for payout, with the bank's reference for it
+    Accepted(String),
+    // Turned down, nothing is paid out
+    Rejected(String),
+}
+
+// A bank rail withdrawals are paid out through. The connector accepts or rejects a
+// withdrawal right away and reports the final outcome later by calling
+// POST /balance/withdraw/callback, which it authenticates itself
+#[async_trait]
+pub trait BankConnector: Send + Sync {
+    // Returns the bank's answer, or an error when it couldn't be reached and the payout
+    // may or may not go ahead. Submitting the same order again must not pay it twice,
+    // it returns the answer the first submission got
+    async fn submit(&self, order: &WithdrawalOrder) -> Result<Submission, String>;
+
+    fn parse_callback(
+        &self,
+        req: &HttpRequest,
+        body: &[u8],
+    ) -> Result<WithdrawalCallback, &'static str>;
+}
+
+// A card or bank acquirer top-ups are charged through. Charges that aren't captured
+// right away are confirmed later by calling POST /balance/top-up/callback
+#[async_trait]
+pub trait FundingProvider: Send + Sync {
+    // Returns the provider's answer, or an error when it couldn't be reached and the
+    // charge may or may not go ahead. Charging the same order again must not charge it
+    // twice, it returns the answer the first charge got
+    async fn charge(&self, order: &TopUpOrder) -> Result<Charge, String>;
+
+    fn parse_callback(&self, req: &HttpRequest, body: &[u8])
+        -> Result<TopUpCallback, &'static str>;
+}
+
+// Picks the connector from BANK_CONNECTOR, only the simulated one ships for now
+pub fn bank_connector() -> Arc<dyn BankConnector> {
+    match env::var("BANK_CONNECTOR")
+        .unwrap_or_else(|_| "simulated".to_string())
+        .as_str()
+    {
+        "simulated" => Arc::new(simulated::SimulatedBankConnector::from_env()),
+        other => panic!("Unknown BANK_CONNECTOR: {}", other),
+    }
+}
+
+// Picks the provider from FUNDING_PROVIDER, only the simulated one ships for now
+pub fn funding_provider() -> Arc<dyn FundingProvider> {
+    match env::var("FUNDING_PROVIDER")
+        .unwrap_or_else(|_| "simulated".to_string())
+        .as_str()
+    {
+        "simulated" => Arc::new(simulated::SimulatedFundingProvider::from_env()),
+        other => panic!("Unknown FUNDING_PROVIDER: {}", other),
+    }
+}
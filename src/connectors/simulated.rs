@@ -0,0 +1,237 @@
+use super::{
+    BankConnector, Charge, FundingProvider, SettlementOutcome, Submission, TopUpCallback,
+    TopUpOrder, WithdrawalCallback, WithdrawalOrder,
+};
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+// Header the simulated bank signs its callbacks with
+pub const SECRET_HEADER: &str = "X-Bank-Secret";
+// Header the simulated acquirer signs its callbacks with
+pub const FUNDING_SECRET_HEADER: &str = "X-Funding-Secret";
+
+#[derive(Serialize, Deserialize)]
+pub struct SimulatedCallback {
+    pub withdrawal_id: Uuid,
+    // SUCCESS or FAILURE
+    pub status: String,
+    pub failure_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SimulatedFundingCallback {
+    pub top_up_id: Uuid,
+    // SUCCESS or FAILURE
+    pub status: String,
+    pub failure_reason: Option<String>,
+}
+
+// A stand-in bank for development and tests. Accounts starting with REJECT are turned
+// down on submit, accounts starting with FAIL fail in the callback, everything else is paid.
+// For accounts starting with TIMEOUT the first answer is lost although the payout goes
+// ahead. With SIMULATED_BANK_CALLBACK_URL set it calls back by itself after a short delay,
+// otherwise the callback has to be posted by hand
+pub struct SimulatedBankConnector {
+    callback_url: Option<String>,
+    secret: String,
+    // Orders paid out already, a resubmitted one isn't paid again
+    submitted: Mutex<HashSet<Uuid>>,
+}
+
+impl SimulatedBankConnector {
+    pub fn from_env() -> Self {
+        Self {
+            callback_url: env::var("SIMULATED_BANK_CALLBACK_URL").ok(),
+            secret: env::var("BANK_CALLBACK_SECRET").expect("BANK_CALLBACK_SECRET must be set"),
+            submitted: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl BankConnector for SimulatedBankConnector {
+    async fn submit(&self, order: &WithdrawalOrder) -> Result<Submission, String> {
+        if order.bank_account.starts_with("REJECT") {
+            return Ok(Submission::Rejected("ACCOUNT_INVALID".to_string()));
+        }
+        let reference = format!("SIM-{}", order.id.simple());
+        if !self.submitted.lock().unwrap().insert(order.id) {
+            return Ok(Submission::Accepted(reference));
+        }
+        println!(
+            "Simulated bank paying {} {} to {}",
+            order.amount, order.currency, order.bank_account
+        );
+
+        if let Some(callback_url) = self.callback_url.clone() {
+            let callback = if order.bank_account.starts_with("FAIL") {
+                SimulatedCallback {
+                    withdrawal_id: order.id,
+                    status: "FAILURE".to_string(),
+                    failure_reason: Some("ACCOUNT_CLOSED".to_string()),
+                }
+            } else {
+                SimulatedCallback {
+                    withdrawal_id: order.id,
+                    status: "SUCCESS".to_string(),
+                    failure_reason: None,
+                }
+            };
+            send_callback(callback_url, SECRET_HEADER, self.secret.clone(), callback);
+        }
+
+        if order.bank_account.starts_with("TIMEOUT") {
+            return Err("Timed out waiting for the bank".to_string());
+        }
+        Ok(Submission::Accepted(reference))
+    }
+
+    fn parse_callback(
+        &self,
+        req: &HttpRequest,
+        body: &[u8],
+    ) -> Result<WithdrawalCallback, &'static str> {
+        verify_secret(req, SECRET_HEADER, &self.secret)?;
+
+        let callback: SimulatedCallback =
+            serde_json::from_slice(body).map_err(|_| "Invalid callback body")?;
+
+        Ok(WithdrawalCallback {
+            withdrawal_id: callback.withdrawal_id,
+            outcome: outcome(&callback.status, callback.failure_reason, "BANK_REJECTED")?,
+        })
+    }
+}
+
+// A stand-in card/bank acquirer for development and tests, the source picks the outcome:
+// tok_success is captured right away, tok_decline is declined, tok_delayed and
+// tok_delayed_decline stay pending until the callback confirms or declines them, and
+// tok_timeout is captured but the first answer is lost.
+// With SIMULATED_FUNDING_CALLBACK_URL set it calls back by itself after a short delay,
+// otherwise the callback has to be posted by hand
+pub struct SimulatedFundingProvider {
+    callback_url: Option<String>,
+    secret: String,
+    // Orders charged already, charging one again only repeats the answer
+    charged: Mutex<HashSet<Uuid>>,
+}
+
+impl SimulatedFundingProvider {
+    pub fn from_env() -> Self {
+        Self {
+            callback_url: env::var("SIMULATED_FUNDING_CALLBACK_URL").ok(),
+            secret: env::var("FUNDING_CALLBACK_SECRET")
+                .expect("FUNDING_CALLBACK_SECRET must be set"),
+            charged: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl FundingProvider for SimulatedFundingProvider {
+    async fn charge(&self, order: &TopUpOrder) -> Result<Charge, String> {
+        let first_charge = self.charged.lock().unwrap().insert(order.id);
+        if first_charge {
+            println!(
+                "Simulated acquirer charging {} {} from {}",
+                order.amount, order.currency, order.source
+            );
+        }
+
+        let reference = format!("SIM-{}", order.id.simple());
+        let (status, failure_reason) = match order.source.as_str() {
+            "tok_success" => return Ok(Charge::Captured(reference)),
+            "tok_timeout" if first_charge => {
+                return Err("Timed out waiting for the acquirer".to_string())
+            }
+            "tok_timeout" => return Ok(Charge::Captured(reference)),
+            "tok_decline" => return Ok(Charge::Declined("CARD_DECLINED".to_string())),
+            "tok_delayed" => ("SUCCESS", None),
+            "tok_delayed_decline" => ("FAILURE", Some("CARD_DECLINED".to_string())),
+            _ => return Ok(Charge::Declined("INVALID_SOURCE".to_string())),
+        };
+
+        if let Some(callback_url) = self.callback_url.clone().filter(|_| first_charge) {
+            let callback = SimulatedFundingCallback {
+                top_up_id: order.id,
+                status: status.to_string(),
+                failure_reason,
+            };
+            send_callback(
+                callback_url,
+                FUNDING_SECRET_HEADER,
+                self.secret.clone(),
+                callback,
+            );
+        }
+
+        Ok(Charge::Pending(reference))
+    }
+
+    fn parse_callback(
+        &self,
+        req: &HttpRequest,
+        body: &[u8],
+    ) -> Result<TopUpCallback, &'static str> {
+        verify_secret(req, FUNDING_SECRET_HEADER, &self.secret)?;
+
+        let callback: SimulatedFundingCallback =
+            serde_json::from_slice(body).map_err(|_| "Invalid callback body")?;
+
+        Ok(TopUpCallback {
+            top_up_id: callback.top_up_id,
+            outcome: outcome(&callback.status, callback.failure_reason, "CHARGE_FAILED")?,
+        })
+    }
+}
+
+// Posts the callback a couple of seconds later, like a real rail would
+fn send_callback<T: Serialize + 'static>(
+    callback_url: String,
+    header: &'static str,
+    secret: String,
+    callback: T,
+) {
+    actix_rt::spawn(async move {
+        actix_rt::time::sleep(Duration::from_secs(2)).await;
+        let _ = awc::Client::default()
+            .post(&callback_url)
+            .insert_header((header, secret))
+            .send_json(&callback)
+            .await;
+    });
+}
+
+fn verify_secret(req: &HttpRequest, header: &str, secret: &str) -> Result<(), &'static str> {
+    let sent = req
+        .headers()
+        .get(header)
+        .map(|secret| secret.as_bytes())
+        .unwrap_or_default();
+    // Compared in constant time so the secret can't be guessed byte by byte from timings
+    if !bool::from(sent.ct_eq(secret.as_bytes())) {
+        return Err("Invalid callback secret");
+    }
+    Ok(())
+}
+
+fn outcome(
+    status: &str,
+    failure_reason: Option<String>,
+    default_reason: &str,
+) -> Result<SettlementOutcome, &'static str> {
+    match status {
+        "SUCCESS" => Ok(SettlementOutcome::Completed),
+        "FAILURE" => Ok(SettlementOutcome::Failed(
+            failure_reason.unwrap_or_else(|| default_reason.to_string()),
+        )),
+        _ => Err("Invalid callback status"),
+    }
+}
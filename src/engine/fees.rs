@@ -0,0 +1,165 @@
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+// House account every fee is paid into, created by the fees migration
+pub const FEE_ACCOUNT_ID: Uuid = Uuid::from_u128(0xfee);
+
+// Fee for `amount` under the caller's most specific matching schedule: one for their tier
+// beats one for the transaction type, which beats the catch-all. Zero without a schedule
+pub async fn transfer_fee(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    transaction_type: &str,
+    currency: &str,
+    amount: Decimal,
+) -> Result<Decimal, sqlx::Error> {
+    let rule = sqlx::query!(
+        r#"
+        SELECT r.flat_fee, r.percentage, r.min_fee, r.max_fee
+        FROM fee_rules r
+        JOIN users u ON u.id = $1
+        WHERE r.currency = $3 AND r.min_amount <= $4
+            AND (r.transaction_type IS NULL OR r.transaction_type = $2)
+            AND (r.user_tier IS NULL OR r.user_tier = u.tier)
+        ORDER BY r.user_tier IS NOT NULL DESC, r.transaction_type IS NOT NULL DESC,
+            r.min_amount DESC
+        LIMIT 1
+        "#,
+        user_id,
+        transaction_type,
+        currency,
+        amount
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(rule) = rule else {
+        return Ok(Decimal::new(0, 0));
+    };
+    let mut fee = rule.flat_fee + amount * rule.percentage / Decimal::ONE_HUNDRED;
+    if let Some(min_fee) = rule.min_fee {
+        fee = fee.max(min_fee);
+    }
+    if let Some(max_fee) = rule.max_fee {
+        fee = fee.min(max_fee);
+    }
+    Ok(fee.round_dp(2))
+}
+
+// Moves an already debited fee into the house account, as a FEE entry for the payer
+// and a FEE_INCOME entry for the house, both on the transfer
+pub async fn charge_fee(
+    conn: &mut sqlx::PgConnection,
+    transfer_id: Uuid,
+    payer_id: Uuid,
+    currency: &str,
+    fee: Decimal,
+    payer_balance: Decimal,
+) -> Result<(), sqlx::Error> {
+    let house = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance + $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        RETURNING balance
+        "#,
+        fee,
+        FEE_ACCOUNT_ID,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions
+            (id, transfer_id, user_id, counterparty_id, transaction_type, amount, currency,
+             status, balance_after)
+        VALUES
+            ($1, $2, $3, $4, 'FEE', $5, $6, 'SUCCESS', $7),
+            ($8, $2, $4, $3, 'FEE_INCOME', $5, $6, 'SUCCESS', $9)
+        "#,
+        Uuid::new_v4(),
+        transfer_id,
+        payer_id,
+        FEE_ACCOUNT_ID,
+        fee,
+        currency,
+        payer_balance,
+        Uuid::new_v4(),
+        house.balance
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Gives a charged fee back to its payer out of the house account, when what it paid for
+// never went through. The FEE and FEE_INCOME entries are reversed and offset by a
+// FEE_REFUND entry for the payer and a FEE_REFUND_SENT entry for the house. Both wallets
+// must already be locked
+pub async fn refund_fee(
+    conn: &mut sqlx::PgConnection,
+    transfer_id: Uuid,
+    payer_id: Uuid,
+    currency: &str,
+    fee: Decimal,
+) -> Result<(), sqlx::Error> {
+    let payer = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance + $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        RETURNING balance
+        "#,
+        fee,
+        payer_id,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let house = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance - $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        RETURNING balance
+        "#,
+        fee,
+        FEE_ACCOUNT_ID,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE transactions SET status = 'REVERSED', updated_at = CURRENT_TIMESTAMP
+        WHERE transfer_id = $1 AND transaction_type IN ('FEE', 'FEE_INCOME')
+        "#,
+        transfer_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions
+            (id, transfer_id, user_id, counterparty_id, transaction_type, amount, currency,
+             status, balance_after)
+        VALUES
+            ($1, $2, $3, $4, 'FEE_REFUND', $5, $6, 'SUCCESS', $7),
+            ($8, $2, $4, $3, 'FEE_REFUND_SENT', $5, $6, 'SUCCESS', $9)
+        "#,
+        Uuid::new_v4(),
+        transfer_id,
+        payer_id,
+        FEE_ACCOUNT_ID,
+        fee,
+        currency,
+        payer.balance,
+        Uuid::new_v4(),
+        house.balance
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Caps on what a user sends out of one currency wallet, None is no cap
+#[derive(Serialize, Deserialize)]
+pub struct TransferLimits {
+    pub per_transaction_amount: Option<Decimal>,
+    pub daily_amount: Option<Decimal>,
+    pub monthly_amount: Option<Decimal>,
+    pub daily_count: Option<i32>,
+    pub monthly_count: Option<i32>,
+}
+
+// What the user sent or withdrew out of the wallet in the current UTC day and month
+pub struct SentUsage {
+    pub daily_amount: Decimal,
+    pub daily_count: i64,
+    pub monthly_amount: Decimal,
+    pub monthly_count: i64,
+}
+
+pub struct UserLimits {
+    pub tier: String,
+    // Set by an admin for this user instead of the tier's limits
+    pub overridden: bool,
+    pub limits: TransferLimits,
+}
+
+// The user's own override if an admin set one, otherwise their tier's limits.
+// A tier without limits configured is uncapped
+pub async fn user_limits(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+) -> Result<UserLimits, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT u.tier, o.user_id IS NOT NULL as "overridden!",
+            CASE WHEN o.user_id IS NOT NULL THEN o.per_transaction_amount
+                ELSE t.per_transaction_amount END as per_transaction_amount,
+            CASE WHEN o.user_id IS NOT NULL THEN o.daily_amount
+                ELSE t.daily_amount END as daily_amount,
+            CASE WHEN o.user_id IS NOT NULL THEN o.monthly_amount
+                ELSE t.monthly_amount END as monthly_amount,
+            CASE WHEN o.user_id IS NOT NULL THEN o.daily_count
+                ELSE t.daily_count END as daily_count,
+            CASE WHEN o.user_id IS NOT NULL THEN o.monthly_count
+                ELSE t.monthly_count END as monthly_count
+        FROM users u
+        LEFT JOIN user_limits o ON o.user_id = u.id
+        LEFT JOIN tier_limits t ON t.tier = u.tier
+        WHERE u.id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(UserLimits {
+        tier: row.tier,
+        overridden: row.overridden,
+        limits: TransferLimits {
+            per_transaction_amount: row.per_transaction_amount,
+            daily_amount: row.daily_amount,
+            monthly_amount: row.monthly_amount,
+            daily_count: row.daily_count,
+            monthly_count: row.monthly_count,
+        },
+    })
+}
+
+pub async fn sent_usage(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    currency: &str,
+) -> Result<SentUsage, sqlx::Error> {
+    // Transfers and withdrawals count, declined ones don't and refunded ones still do.
+    // Active holds count from when they were placed, capturing one sends it without
+    // checking limits again
+    let usage = sqlx::query!(
+        r#"
+        SELECT
+            COALESCE(SUM(amount) FILTER (
+                WHERE created_at >= date_trunc('day', NOW(), 'UTC')), 0) as "daily_amount!",
+            COUNT(*) FILTER (
+                WHERE created_at >= date_trunc('day', NOW(), 'UTC')) as "daily_count!",
+            COALESCE(SUM(amount), 0) as "monthly_amount!",
+            COUNT(*) as "monthly_count!"
+        FROM (
+            SELECT amount, created_at FROM transactions
+            WHERE user_id = $1 AND currency = $2 AND transaction_type IN ('SENT', 'WITHDRAWAL')
+                AND status <> 'FAILURE' AND created_at >= date_trunc('month', NOW(), 'UTC')
+            UNION ALL
+            SELECT amount, created_at FROM holds
+            WHERE user_id = $1 AND currency = $2 AND status = 'ACTIVE'
+                AND created_at >= date_trunc('month', NOW(), 'UTC')
+        ) sent
+        "#,
+        user_id,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(SentUsage {
+        daily_amount: usage.daily_amount,
+        daily_count: usage.daily_count,
+        monthly_amount: usage.monthly_amount,
+        monthly_count: usage.monthly_count,
+    })
+}
+
+// The first limit sending `amount` would go over, as its failure reason. Runs under the
+// sender's wallet lock, so concurrent transfers can't both slip under a limit
+pub async fn exceeded_limit(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    currency: &str,
+    amount: Decimal,
+) -> Result<Option<&'static str>, sqlx::Error> {
+    let limits = user_limits(&mut *conn, user_id).await?.limits;
+    if limits
+        .per_transaction_amount
+        .is_some_and(|limit| amount > limit)
+    {
+        return Ok(Some("TRANSACTION_AMOUNT_LIMIT"));
+    }
+
+    let usage = sent_usage(&mut *conn, user_id, currency).await?;
+    if limits
+        .daily_amount
+        .is_some_and(|limit| usage.daily_amount + amount > limit)
+    {
+        return Ok(Some("DAILY_AMOUNT_LIMIT"));
+    }
+    if limits
+        .monthly_amount
+        .is_some_and(|limit| usage.monthly_amount + amount > limit)
+    {
+        return Ok(Some("MONTHLY_AMOUNT_LIMIT"));
+    }
+    if limits
+        .daily_count
+        .is_some_and(|limit| usage.daily_count >= i64::from(limit))
+    {
+        return Ok(Some("DAILY_COUNT_LIMIT"));
+    }
+    if limits
+        .monthly_count
+        .is_some_and(|limit| usage.monthly_count >= i64::from(limit))
+    {
+        return Ok(Some("MONTHLY_COUNT_LIMIT"));
+    }
+    Ok(None)
+}
+
+// Message shown for each reason exceeded_limit returns
+pub fn limit_message(reason: &str) -> &'static str {
+    match reason {
+        "TRANSACTION_AMOUNT_LIMIT" => "Amount exceeds the per-transaction limit",
+        "DAILY_AMOUNT_LIMIT" => "Transfer exceeds the daily amount limit",
+        "MONTHLY_AMOUNT_LIMIT" => "Transfer exceeds the monthly amount limit",
+        "DAILY_COUNT_LIMIT" => "Daily transfer count limit reached",
+        "MONTHLY_COUNT_LIMIT" => "Monthly transfer count limit reached",
+        _ => "Transfer limit exceeded",
+    }
+}
@@ -0,0 +1,536 @@
+pub mod fees;
+pub mod limits;
+pub mod request;
+pub mod retry;
+
+use crate::utils::{
+    currency::{convert, parse_currency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::HttpResponse;
+use fees::{charge_fee, transfer_fee, FEE_ACCOUNT_ID};
+use limits::{exceeded_limit, limit_message};
+pub use request::{validate_details, SendTransactionRequest};
+use retry::{backoff, is_transient, retry, MAX_ATTEMPTS};
+use rust_decimal::Decimal;
+use sqlx::Acquire;
+use uuid::Uuid;
+
+pub enum TransferError {
+    // The request itself is invalid, nothing was attempted
+    Invalid(&'static str),
+    ReceiverNotFound {
+        currency: String,
+    },
+    // `received_amount` and `received_currency` are what the receiver would have got
+    InsufficientBalance {
+        receiver_id: Uuid,
+        currency: String,
+        received_amount: Decimal,
+        received_currency: String,
+    },
+    // Over one of the sender's transfer limits, `limit` is the failure reason
+    LimitExceeded {
+        receiver_id: Uuid,
+        currency: String,
+        received_amount: Decimal,
+        received_currency: String,
+        limit: &'static str,
+    },
+    // Lost a deadlock or serialization conflict to a concurrent transaction, running it
+    // again in a new transaction should go through
+    Contention,
+    Database(&'static str),
+}
+
+impl TransferError {
+    // Declines that are kept as FAILURE entries
+    pub fn failure_reason(&self) -> Option<&'static str> {
+        match self {
+            TransferError::ReceiverNotFound { .. } => Some("RECEIVER_NOT_FOUND"),
+            TransferError::InsufficientBalance { .. } => Some("INSUFFICIENT_BALANCE"),
+            TransferError::LimitExceeded { limit, .. } => Some(limit),
+            TransferError::Invalid(_) | TransferError::Contention | TransferError::Database(_) => {
+                None
+            }
+        }
+    }
+
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            TransferError::Invalid(msg) => (400, msg),
+            TransferError::ReceiverNotFound { .. } => (404, "Receiver not found"),
+            TransferError::InsufficientBalance { .. } => (400, "Insufficient balance"),
+            TransferError::LimitExceeded { limit, .. } => (403, limit_message(limit)),
+            TransferError::Contention => (503, "Too many concurrent transfers, try again"),
+            TransferError::Database(msg) => (500, msg),
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let (status_code, message) = self.status();
+        json_response(ApiResponse::<MessageData>::error(
+            status_code,
+            message.to_string(),
+        ))
+    }
+}
+
+pub struct TransferReceipt {
+    pub transfer_id: Uuid,
+    pub currency: String,
+    pub received_amount: Decimal,
+    pub received_currency: String,
+    pub fee: Decimal,
+    // Sender's balance after the transfer and its fee
+    pub balance: Decimal,
+}
+
+// Moves money between two wallets inside the caller's transaction
+pub async fn execute_transfer(
+    conn: &mut sqlx::PgConnection,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+) -> Result<TransferReceipt, TransferError> {
+    move_funds(conn, sender_id, send_request, None).await
+}
+
+// Pays out a hold the sender placed earlier. Its limits were checked and its fee reserved
+// when it was placed, so the capture is never charged more than `reserved_fee`
+pub async fn capture_transfer(
+    conn: &mut sqlx::PgConnection,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+    reserved_fee: Decimal,
+) -> Result<TransferReceipt, TransferError> {
+    move_funds(conn, sender_id, send_request, Some(reserved_fee)).await
+}
+
+async fn move_funds(
+    conn: &mut sqlx::PgConnection,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+    reserved_fee: Option<Decimal>,
+) -> Result<TransferReceipt, TransferError> {
+    // Validate amount is positive
+    if send_request.amount <= Decimal::new(0, 0) {
+        return Err(TransferError::Invalid("Amount must be positive"));
+    }
+
+    let currency =
+        parse_currency(send_request.currency.as_deref()).map_err(TransferError::Invalid)?;
+    validate_details(send_request.memo.as_deref(), send_request.metadata.as_ref())
+        .map_err(TransferError::Invalid)?;
+
+    // Both sides of a plain transfer use the same currency
+    let receive_currency = match send_request.receive_currency.as_deref() {
+        Some(receive_currency) => {
+            parse_currency(Some(receive_currency)).map_err(TransferError::Invalid)?
+        }
+        None => currency.clone(),
+    };
+    if receive_currency != currency && send_request.quote_id.is_none() {
+        return Err(TransferError::Invalid(
+            "Cross-currency transfers require a conversion quote",
+        ));
+    }
+
+    // Use up the quote, so each quoted rate converts exactly once
+    let received_amount = match send_request.quote_id {
+        None => send_request.amount,
+        Some(quote_id) => {
+            let quote = sqlx::query!(
+                r#"
+                UPDATE fx_quotes SET used_at = CURRENT_TIMESTAMP
+                WHERE id = $1 AND user_id = $2 AND used_at IS NULL AND expires_at > NOW()
+                RETURNING from_currency, to_currency, rate
+                "#,
+                quote_id,
+                sender_id
+            )
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(database_error("Database error"))?
+            .ok_or(TransferError::Invalid("Quote not found or expired"))?;
+
+            if quote.from_currency != currency || quote.to_currency != receive_currency {
+                return Err(TransferError::Invalid(
+                    "Quote does not match the transfer currencies",
+                ));
+            }
+            convert(send_request.amount, quote.rate)
+        }
+    };
+
+    // Get receiver's ID and verify they exist. The key share lock only keeps the user
+    // from being deleted, wallet locks are what order concurrent transfers
+    let receiver = sqlx::query!(
+        "SELECT id FROM users WHERE email = $1 FOR KEY SHARE",
+        send_request.email
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(database_error("Database error"))?
+    .ok_or_else(|| TransferError::ReceiverNotFound {
+        currency: currency.clone(),
+    })?;
+    if receiver.id == sender_id {
+        return Err(TransferError::Invalid("Cannot send money to yourself"));
+    }
+
+    // The sender pays the fee on top, in the currency they send
+    let fee = transfer_fee(
+        &mut *conn,
+        sender_id,
+        "SENT",
+        &currency,
+        send_request.amount,
+    )
+    .await
+    .map_err(database_error("Database error"))?;
+    let fee = reserved_fee.map_or(fee, |reserved_fee| fee.min(reserved_fee));
+
+    let mut wallets = vec![
+        (sender_id, currency.clone()),
+        (receiver.id, receive_currency.clone()),
+    ];
+    if fee > Decimal::new(0, 0) {
+        wallets.push((FEE_ACCOUNT_ID, currency.clone()));
+    }
+    lock_wallets(&mut *conn, &wallets)
+        .await
+        .map_err(database_error("Database error"))?;
+
+    // Get sender's available balance, funds reserved by holds can't be spent
+    let sender_balance = sqlx::query!(
+        r#"
+        SELECT balance - held as "available!" FROM wallets
+        WHERE user_id = $1 AND currency = $2
+        "#,
+        sender_id,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(database_error("Database error"))?
+    .available;
+
+    // Limits are checked under the sender's wallet lock so concurrent transfers see
+    // each other's usage
+    let limit = match reserved_fee {
+        Some(_) => None,
+        None => exceeded_limit(&mut *conn, sender_id, &currency, send_request.amount)
+            .await
+            .map_err(database_error("Database error"))?,
+    };
+    if let Some(limit) = limit {
+        return Err(TransferError::LimitExceeded {
+            receiver_id: receiver.id,
+            currency,
+            received_amount,
+            received_currency: receive_currency,
+            limit,
+        });
+    }
+
+    // Check if sender has sufficient balance
+    if sender_balance < send_request.amount + fee {
+        return Err(TransferError::InsufficientBalance {
+            receiver_id: receiver.id,
+            currency,
+            received_amount,
+            received_currency: receive_currency,
+        });
+    }
+
+    // Update sender's balance
+    let updated_sender = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance - $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        RETURNING balance
+        "#,
+        send_request.amount + fee,
+        sender_id,
+        currency
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(database_error("Failed to update sender balance"))?;
+
+    // Update receiver's balance
+    let updated_receiver = sqlx::query!(
+        r#"
+        INSERT INTO wallets (user_id, currency, balance)
+        VALUES ($2, $3, $1)
+        ON CONFLICT (user_id, currency)
+        DO UPDATE SET balance = wallets.balance + EXCLUDED.balance,
+            updated_at = CURRENT_TIMESTAMP
+        RETURNING balance
+        "#,
+        received_amount,
+        receiver.id,
+        receive_currency
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(database_error("Failed to update receiver balance"))?;
+
+    // Create linked transaction records for both sender and receiver
+    let transfer_id = Uuid::new_v4();
+    let sender_transaction_id = Uuid::new_v4();
+    let receiver_transaction_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions
+            (id, transfer_id, user_id, counterparty_id, transaction_type, amount, currency,
+             status, balance_after, memo, metadata, fx_quote_id)
+        VALUES
+            ($1, $2, $3, $5, 'SENT', $4, $10, 'SUCCESS', $7, $9, $14, $13),
+            ($6, $2, $5, $3, 'RECEIVED', $11, $12, 'SUCCESS', $8, $9, $14, $13)
+        "#,
+        sender_transaction_id,
+        transfer_id,
+        sender_id,
+        send_request.amount,
+        receiver.id,
+        receiver_transaction_id,
+        // The fee entry comes right after and takes the balance the rest of the way
+        updated_sender.balance + fee,
+        updated_receiver.balance,
+        send_request.memo,
+        currency,
+        received_amount,
+        receive_currency,
+        send_request.quote_id,
+        send_request.metadata.clone().map(serde_json::Value::Object)
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(database_error("Failed to create transaction records"))?;
+
+    if fee > Decimal::new(0, 0) {
+        charge_fee(
+            &mut *conn,
+            transfer_id,
+            sender_id,
+            &currency,
+            fee,
+            updated_sender.balance,
+        )
+        .await
+        .map_err(database_error("Failed to charge fee"))?;
+    }
+
+    Ok(TransferReceipt {
+        transfer_id,
+        currency,
+        received_amount,
+        received_currency: receive_currency,
+        fee,
+        balance: updated_sender.balance,
+    })
+}
+
+// Locks wallets in (user_id, currency) order, creating the missing ones first. Everything
+// that moves money between wallets locks them through here, so concurrent transfers over
+// the same wallets wait on each other instead of deadlocking
+pub async fn lock_wallets(
+    conn: &mut sqlx::PgConnection,
+    wallets: &[(Uuid, String)],
+) -> Result<(), sqlx::Error> {
+    let (user_ids, currencies): (Vec<Uuid>, Vec<String>) = wallets.iter().cloned().unzip();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO wallets (user_id, currency)
+        SELECT user_id, currency FROM UNNEST($1::uuid[], $2::text[]) AS w(user_id, currency)
+        ORDER BY user_id, currency
+        ON CONFLICT (user_id, currency) DO NOTHING
+        "#,
+        &user_ids,
+        &currencies
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        SELECT user_id FROM wallets
+        WHERE (user_id, currency) IN (SELECT * FROM UNNEST($1::uuid[], $2::text[]))
+        ORDER BY user_id, currency
+        FOR UPDATE
+        "#,
+        &user_ids,
+        &currencies
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Runs a transfer for a background job inside the job's transaction, running it again
+// when it loses a conflict. Declines are recorded before they are returned
+pub async fn run_transfer(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+) -> Result<Uuid, TransferError> {
+    let mut attempt = 1;
+    loop {
+        // A declined transfer only rolls back its own savepoint, which also lets go of
+        // the locks a conflicting attempt took
+        let mut savepoint = tx
+            .begin()
+            .await
+            .map_err(database_error("Failed to start transaction"))?;
+        match execute_transfer(&mut savepoint, sender_id, send_request).await {
+            Ok(receipt) => {
+                savepoint
+                    .commit()
+                    .await
+                    .map_err(database_error("Failed to commit transaction"))?;
+                return Ok(receipt.transfer_id);
+            }
+            Err(TransferError::Contention) if attempt < MAX_ATTEMPTS => {
+                savepoint
+                    .rollback()
+                    .await
+                    .map_err(database_error("Failed to roll back transaction"))?;
+                backoff(attempt).await;
+                attempt += 1;
+            }
+            Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                return Err(error)
+            }
+            Err(error) => {
+                savepoint
+                    .rollback()
+                    .await
+                    .map_err(database_error("Failed to roll back transaction"))?;
+                record_failed_transfer(&mut **tx, sender_id, send_request, &error).await;
+                return Err(error);
+            }
+        }
+    }
+}
+
+// Runs a transfer in a transaction of its own, running it again when it loses a
+// conflict with concurrent transfers. Declines are recorded once the transfer gives up
+pub async fn transfer(
+    pool: &sqlx::PgPool,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+) -> Result<TransferReceipt, TransferError> {
+    let error = match retry(async || try_transfer(pool, sender_id, send_request).await).await {
+        Ok(receipt) => return Ok(receipt),
+        Err(error) => error,
+    };
+
+    // Declines are recorded outside the rolled back transaction
+    record_failed_transfer(pool, sender_id, send_request, &error).await;
+    Err(error)
+}
+
+async fn try_transfer(
+    pool: &sqlx::PgPool,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+) -> Result<TransferReceipt, TransferError> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(database_error("Failed to start transaction"))?;
+    let receipt = execute_transfer(&mut tx, sender_id, send_request).await?;
+    // Serialization failures can surface as late as the commit
+    tx.commit()
+        .await
+        .map_err(database_error("Failed to commit transaction"))?;
+    Ok(receipt)
+}
+
+// Maps a database error to Contention when retrying can fix it, to `message` otherwise
+pub fn database_error(message: &'static str) -> impl FnOnce(sqlx::Error) -> TransferError {
+    move |e| {
+        if is_transient(&e) {
+            TransferError::Contention
+        } else {
+            TransferError::Database(message)
+        }
+    }
+}
+
+// Declined transfers are kept as FAILURE entries so they show up in history and webhooks.
+// The receiver's entry is in what they would have received
+pub async fn record_failed_transfer<'e, E: sqlx::PgExecutor<'e>>(
+    executor: E,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+    error: &TransferError,
+) {
+    let (currency, receiver_id, received_amount, received_currency) = match error {
+        TransferError::ReceiverNotFound { currency } => (currency, None, None, None),
+        TransferError::InsufficientBalance {
+            receiver_id,
+            currency,
+            received_amount,
+            received_currency,
+        }
+        | TransferError::LimitExceeded {
+            receiver_id,
+            currency,
+            received_amount,
+            received_currency,
+            ..
+        } => (
+            currency,
+            Some(*receiver_id),
+            Some(*received_amount),
+            Some(received_currency),
+        ),
+        TransferError::Invalid(_) | TransferError::Contention | TransferError::Database(_) => {
+            return
+        }
+    };
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO transactions
+            (id, transfer_id, user_id, counterparty_id, transaction_type, amount, currency,
+             status, memo, metadata, failure_reason, balance_after)
+        SELECT entry.*, COALESCE((
+            SELECT balance FROM wallets
+            WHERE user_id = entry.user_id AND currency = entry.currency
+        ), 0)
+        FROM (
+            SELECT $1::uuid as id, $2::uuid as transfer_id, $3::uuid as user_id,
+                $4::uuid as counterparty_id, 'SENT'::transaction_type as transaction_type,
+                $5::decimal as amount, $9::text as currency,
+                'FAILURE'::transaction_status as status, $6::text as memo,
+                $10::jsonb as metadata, $7::text as failure_reason
+            UNION ALL
+            SELECT $8, $2, $4, $3, 'RECEIVED', $11::decimal, $12::text, 'FAILURE', $6, $10, $7
+            WHERE $4 IS NOT NULL
+        ) entry
+        "#,
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        sender_id,
+        receiver_id,
+        send_request.amount,
+        send_request.memo,
+        error.failure_reason(),
+        Uuid::new_v4(),
+        currency,
+        send_request.metadata.clone().map(serde_json::Value::Object),
+        received_amount,
+        received_currency
+    )
+    .execute(executor)
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to record failed transfer: {}", e);
+    }
+}
@@ -0,0 +1,76 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const MAX_MEMO_LENGTH: usize = 500;
+const MAX_METADATA_KEYS: usize = 20;
+const MAX_METADATA_KEY_LENGTH: usize = 40;
+const MAX_METADATA_VALUE_LENGTH: usize = 200;
+// Memo and metadata ride along in webhook notifications, which pg_notify caps at 8000
+// bytes. Together they stay well below that, leaving room for the rest of the payload
+const MAX_DETAILS_BYTES: usize = 4000;
+
+#[derive(Serialize, Deserialize)]
+pub struct SendTransactionRequest {
+    pub amount: Decimal,
+    pub email: String,
+    pub memo: Option<String>,
+    // Flat key/value pairs for the sender's own bookkeeping, e.g. an order id
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    pub currency: Option<String>,
+    // Currency the receiver should be credited in, defaults to `currency`
+    pub receive_currency: Option<String>,
+    // Quote from POST /fx/quote, required when the currencies differ
+    pub quote_id: Option<Uuid>,
+}
+
+// Keeps the memo short and metadata flat and small: string, number or boolean values under
+// bounded keys. Both are measured in bytes as Postgres writes them into the notification
+pub fn validate_details(
+    memo: Option<&str>,
+    metadata: Option<&serde_json::Map<String, serde_json::Value>>,
+) -> Result<(), &'static str> {
+    let mut bytes = 0;
+    if let Some(memo) = memo {
+        if memo.chars().count() > MAX_MEMO_LENGTH {
+            return Err("memo can be at most 500 characters");
+        }
+        bytes += json_length(&serde_json::Value::from(memo));
+    }
+
+    if metadata.is_some_and(|metadata| metadata.len() > MAX_METADATA_KEYS) {
+        return Err("metadata can have at most 20 keys");
+    }
+    for (key, value) in metadata.into_iter().flatten() {
+        if key.is_empty() || key.chars().count() > MAX_METADATA_KEY_LENGTH {
+            return Err("metadata keys must be 1 to 40 characters");
+        }
+        let value_length = match value {
+            serde_json::Value::String(value) => value.chars().count(),
+            serde_json::Value::Number(value) => value.to_string().len(),
+            serde_json::Value::Bool(_) => 0,
+            _ => return Err("metadata values must be strings, numbers or booleans"),
+        };
+        if value_length > MAX_METADATA_VALUE_LENGTH {
+            return Err("metadata values can be at most 200 characters");
+        }
+        // Quoted key, value and the ": " and ", " jsonb puts around them
+        bytes += json_length(&serde_json::Value::from(key.as_str())) + json_length(value) + 4;
+    }
+
+    if bytes > MAX_DETAILS_BYTES {
+        return Err("memo and metadata can be at most 4000 bytes together");
+    }
+    Ok(())
+}
+
+// Bytes a JSON value takes once escaped. jsonb writes numbers out without an exponent,
+// 1e300 becomes 301 digits, which is what Display does for floats as well
+fn json_length(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Number(number) if !number.is_i64() && !number.is_u64() => number
+            .as_f64()
+            .map_or(number.to_string().len(), |float| float.to_string().len()),
+        value => value.to_string().len(),
+    }
+}
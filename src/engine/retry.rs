@@ -0,0 +1,47 @@
+use super::TransferError;
+use rand::Rng;
+use std::time::Duration;
+
+// Attempts a transfer gets before a conflict is reported to the caller
+pub const MAX_ATTEMPTS: u32 = 5;
+
+const BASE_DELAY_MS: u64 = 10;
+const MAX_DELAY_MS: u64 = 200;
+
+// Deadlocks and serialization failures roll back the losing transaction and go away when
+// it runs again, every other database error would just fail the same way twice
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => matches!(
+            e.code().as_deref(),
+            // serialization_failure, deadlock_detected
+            Some("40001") | Some("40P01")
+        ),
+        _ => false,
+    }
+}
+
+// Waits before attempt `attempt + 1`: exponential up to MAX_DELAY_MS with full jitter, so
+// transfers that collided once don't collide again in lockstep
+pub async fn backoff(attempt: u32) {
+    let ceiling = (BASE_DELAY_MS << attempt.min(10)).min(MAX_DELAY_MS);
+    let delay = rand::thread_rng().gen_range(0..=ceiling);
+    actix_rt::time::sleep(Duration::from_millis(delay)).await;
+}
+
+// Runs `attempt` until it stops losing conflicts with concurrent transfers, each run in a
+// transaction of its own. Every caller that moves money goes through here
+pub async fn retry<T>(
+    mut attempt: impl AsyncFnMut() -> Result<T, TransferError>,
+) -> Result<T, TransferError> {
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(TransferError::Contention) if attempts < MAX_ATTEMPTS => {
+                backoff(attempts).await;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
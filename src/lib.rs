@@ -1,2 +1,4 @@
-pub mod routes;
-pub mod utils;
+pub mod connectors;
+pub mod engine;
+pub mod routes;
+pub mod utils;
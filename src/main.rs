@@ -4,14 +4,44 @@ use env_logger::Env;
 use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
 use rdkafka::client::DefaultClientContext;
 use rdkafka::ClientConfig;
+use routes::api_keys::{create_api_key, get_api_keys, revoke_api_key};
 use routes::balance::{add_amount, get_balance};
+use routes::batch::send_batch;
+use routes::fees::{get_fee_rules, quote_transfer, set_fee_schedule, set_user_tier};
+use routes::fx::{create_quote, load_rates_from_csv, set_rates};
 use routes::health::health;
+use routes::holds::{capture_hold, create_hold, get_holds, process_hold_expiry, void_hold};
+use routes::jwks::jwks;
+use routes::limits::{get_limits, get_tier_limits, set_tier_limits, set_user_limits};
 use routes::merchant::{listen_to_notifications, process_webhooks, webhook_listener};
-use routes::transactions::{get_transactions, send_transaction};
-use routes::user::{get_user, login, register};
+use routes::payment_requests::{
+    approve_payment_request, cancel_payment_request, create_payment_request,
+    decline_payment_request, get_payment_requests, process_payment_request_expiry,
+};
+use routes::reconciliation::{get_reconciliation, reconcile};
+use routes::scheduled::{
+    cancel_scheduled_transfer, create_scheduled_transfer, get_scheduled_transfers,
+    process_scheduled_transfers,
+};
+use routes::standing_orders::{
+    cancel_standing_order, create_standing_order, get_standing_order_runs, get_standing_orders,
+    pause_standing_order, process_standing_orders, resume_standing_order,
+};
+use routes::statements::export_transactions;
+use routes::top_ups::{create_top_up, get_top_ups, process_top_up_charges, top_up_callback};
+use routes::transactions::{
+    get_transaction, get_transactions, refund_transaction, send_transaction,
+};
+use routes::user::{get_user, login, logout, refresh_token, register, set_user_role};
+use routes::withdrawals::{
+    create_withdrawal, get_withdrawals, process_withdrawal_submissions, withdrawal_callback,
+};
 use sqlx::postgres::PgPoolOptions;
 use std::env;
+use utils::keys::JwtKeys;
 
+mod connectors;
+mod engine;
 mod routes;
 mod utils;
 
@@ -59,6 +89,41 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to migrate the database");
 
+    // `reconcile` checks every wallet against the ledger and exits, non-zero on drift
+    if env::args().nth(1).as_deref() == Some("reconcile") {
+        let report = reconcile(&pool)
+            .await
+            .expect("Failed to reconcile the ledger");
+        for drift in &report.drifts {
+            println!(
+                "{} {}: wallet {} ledger {} drift {} last balance_after {} broken entries {}",
+                drift.email,
+                drift.currency,
+                drift.wallet_balance,
+                drift.ledger_balance,
+                drift.drift,
+                drift
+                    .last_balance_after
+                    .map_or("-".to_string(), |balance| balance.to_string()),
+                drift.broken_entries
+            );
+        }
+        println!(
+            "Checked {} wallets, {} with drift",
+            report.wallets_checked,
+            report.drifts.len()
+        );
+        std::process::exit(if report.drifts.is_empty() { 0 } else { 1 });
+    }
+
+    // Load FX rates from a local file, when one is configured
+    if let Ok(fx_rates_file) = env::var("FX_RATES_FILE") {
+        match load_rates_from_csv(&pool, &fx_rates_file).await {
+            Ok(count) => println!("Loaded {} FX rates from {}", count, fx_rates_file),
+            Err(e) => eprintln!("Failed to load FX rates from {}: {}", fx_rates_file, e),
+        }
+    }
+
     // Create Kafka topic
     create_kafka_topic().await;
 
@@ -76,6 +141,59 @@ async fn main() -> std::io::Result<()> {
         });
     });
 
+    //seprate worker, runs scheduled transfers once they are due
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_scheduled_transfers().await;
+        });
+    });
+
+    //seprate worker, runs standing orders on their schedule
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_standing_orders().await;
+        });
+    });
+
+    //seprate worker, expires payment requests that were not paid in time
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_payment_request_expiry().await;
+        });
+    });
+
+    // Bank rail withdrawals are paid out through
+    let bank_connector = connectors::bank_connector();
+
+    //seprate worker, submits withdrawals the bank hasn't answered for again
+    let connector = bank_connector.clone();
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_withdrawal_submissions(connector).await;
+        });
+    });
+
+    // Card or bank acquirer top-ups are charged through
+    let funding_provider = connectors::funding_provider();
+
+    //seprate worker, charges top-ups the provider hasn't answered for again
+    let provider = funding_provider.clone();
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_top_up_charges(provider).await;
+        });
+    });
+
+    //seprate worker, releases holds nobody captured in time
+    std::thread::spawn(|| {
+        actix_rt::System::new().block_on(async {
+            process_hold_expiry().await;
+        });
+    });
+
+    // Access tokens are signed and verified with these, see JWT_KEYS_DIR
+    let jwt_keys = web::Data::new(JwtKeys::from_env());
+
     // Configure rate limiting
     let governor_conf = GovernorConfigBuilder::default()
         .seconds_per_request(1) // Allow 1 requests per second
@@ -88,19 +206,68 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(bank_connector.clone()))
+            .app_data(web::Data::from(funding_provider.clone()))
+            .app_data(jwt_keys.clone())
             // Add security middleware
             .wrap(Governor::new(&governor_conf))
             .wrap(middleware::Compress::default())
             // Enable max body size of ~1mb
             .app_data(web::JsonConfig::default().limit(1000000))
             .service(health)
+            .service(jwks)
             .service(register)
             .service(login)
+            .service(refresh_token)
+            .service(logout)
             .service(get_user)
+            .service(create_api_key)
+            .service(get_api_keys)
+            .service(revoke_api_key)
             .service(get_balance)
             .service(add_amount)
+            .service(create_top_up)
+            .service(get_top_ups)
+            .service(top_up_callback)
+            .service(create_withdrawal)
+            .service(get_withdrawals)
+            .service(withdrawal_callback)
             .service(get_transactions)
+            .service(export_transactions)
+            .service(get_transaction)
+            .service(quote_transfer)
             .service(send_transaction)
+            .service(send_batch)
+            .service(refund_transaction)
+            .service(create_scheduled_transfer)
+            .service(get_scheduled_transfers)
+            .service(cancel_scheduled_transfer)
+            .service(create_standing_order)
+            .service(get_standing_orders)
+            .service(get_standing_order_runs)
+            .service(pause_standing_order)
+            .service(resume_standing_order)
+            .service(cancel_standing_order)
+            .service(create_payment_request)
+            .service(get_payment_requests)
+            .service(approve_payment_request)
+            .service(decline_payment_request)
+            .service(cancel_payment_request)
+            .service(create_hold)
+            .service(get_holds)
+            .service(capture_hold)
+            .service(void_hold)
+            .service(create_quote)
+            .service(set_rates)
+            .service(get_fee_rules)
+            .service(set_fee_schedule)
+            .service(set_user_tier)
+            .service(set_user_role)
+            .service(get_limits)
+            .service(get_tier_limits)
+            .service(set_tier_limits)
+            .service(set_user_limits)
+            .service(get_reconciliation)
             .service(webhook_listener)
     })
     .bind(("0.0.0.0", 8080))?
@@ -0,0 +1,188 @@
+use crate::utils::{
+    auth::{self, Authenticated, MerchantRole, API_KEY_SCOPES},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, Responder};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// Keys drawn before giving up on finding a free prefix
+const MAX_KEY_ATTEMPTS: u32 = 3;
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+    // Never expires when omitted
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyResponse {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_used_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    revoked_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKeyResponse {
+    // Shown once, only its hash is kept
+    key: String,
+    #[serde(flatten)]
+    api_key: ApiKeyResponse,
+}
+
+#[post("/api-keys")]
+pub async fn create_api_key(
+    claims: Authenticated<MerchantRole>,
+    key_request: web::Json<CreateApiKeyRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let name = key_request.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "name must be 1 to 100 characters".to_string(),
+        ));
+    }
+
+    let mut scopes = Vec::with_capacity(key_request.scopes.len());
+    for scope in &key_request.scopes {
+        if !API_KEY_SCOPES.contains(&scope.as_str()) {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                format!("Unknown scope {}", scope),
+            ));
+        }
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+    if scopes.is_empty() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "At least one scope is required".to_string(),
+        ));
+    }
+
+    if key_request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+
+    // Prefixes are unique, on the rare clash with an existing key a new one is drawn
+    let mut attempts = 1;
+    let (key, api_key) = loop {
+        let (prefix, key) = auth::generate_api_key();
+        let api_key = sqlx::query_as!(
+            ApiKeyResponse,
+            r#"
+            INSERT INTO api_keys (id, user_id, name, prefix, secret_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+            "#,
+            Uuid::new_v4(),
+            claims.sub,
+            name,
+            prefix,
+            auth::hash_secret(&key),
+            &scopes,
+            key_request.expires_at
+        )
+        .fetch_one(&**pool)
+        .await;
+        match api_key {
+            Err(sqlx::Error::Database(e))
+                if e.is_unique_violation() && attempts < MAX_KEY_ATTEMPTS =>
+            {
+                attempts += 1
+            }
+            api_key => break (key, api_key),
+        }
+    };
+
+    match api_key {
+        Ok(api_key) => json_response(ApiResponse::success(CreatedApiKeyResponse { key, api_key })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to create API key".to_string(),
+        )),
+    }
+}
+
+#[get("/api-keys")]
+pub async fn get_api_keys(
+    claims: Authenticated<MerchantRole>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Revoked keys stay listed so their last use can still be looked up
+    let api_keys = sqlx::query_as!(
+        ApiKeyResponse,
+        r#"
+        SELECT id, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+        FROM api_keys
+        WHERE user_id = $1
+        ORDER BY created_at DESC, id
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match api_keys {
+        Ok(api_keys) => json_response(ApiResponse::success(api_keys)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch API keys".to_string(),
+        )),
+    }
+}
+
+#[post("/api-keys/{id}/revoke")]
+pub async fn revoke_api_key(
+    claims: Authenticated<MerchantRole>,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Revoking twice keeps the first revocation time
+    let api_key = sqlx::query_as!(
+        ApiKeyResponse,
+        r#"
+        UPDATE api_keys SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP)
+        WHERE id = $1 AND user_id = $2
+        RETURNING id, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+        "#,
+        path.into_inner(),
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match api_key {
+        Ok(Some(api_key)) => json_response(ApiResponse::success(api_key)),
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "API key not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to revoke API key".to_string(),
+        )),
+    }
+}
@@ -1,95 +1,153 @@
-use crate::utils::{
-    auth,
-    response::{json_response, ApiResponse, MessageData},
-};
-use actix_web::{get, post, web, Responder};
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use sqlx::types::Uuid;
-
-#[derive(Deserialize)]
-pub struct AddBalanceRequest {
-    amount: Decimal,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct BalanceResponse {
-    balance: Decimal,
-}
-
-#[get("/balance")]
-pub async fn get_balance(
-    req: actix_web::HttpRequest,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Verify token and get claims
-    let claims = match auth::verify_request_token(&req) {
-        Ok(claims) => claims,
-        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
-    };
-
-    let user = sqlx::query!("SELECT balance FROM users WHERE id = $1", claims.sub)
-        .fetch_optional(&**pool)
-        .await;
-
-    match user {
-        Ok(Some(user)) => json_response(ApiResponse::success(BalanceResponse {
-            balance: user.balance,
-        })),
-        _ => json_response(ApiResponse::<MessageData>::error(
-            404,
-            "User not found".to_string(),
-        )),
-    }
-}
-
-#[post("/balance/add")]
-pub async fn add_amount(
-    req: actix_web::HttpRequest,
-    add_request: web::Json<AddBalanceRequest>,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Verify token and get claims
-    let claims = match auth::verify_request_token(&req) {
-        Ok(claims) => claims,
-        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
-    };
-
-    // Validate amount is positive
-    if add_request.amount <= Decimal::new(0, 0) {
-        return json_response(ApiResponse::<MessageData>::error(
-            400,
-            "Amount must be positive".to_string(),
-        ));
-    }
-
-    // Update balance and create transaction record in a transaction
-    let result = sqlx::query!(
-        r#"
-        WITH updated_user AS (
-            UPDATE users 
-            SET balance = balance + $1 
-            WHERE id = $2 
-            RETURNING balance
-        )
-        INSERT INTO transactions (id, user_id, transaction_type, amount, status)
-        VALUES ($3, $2, 'RECEIVED', $1, 'SUCCESS')
-        RETURNING (SELECT balance FROM updated_user)
-        "#,
-        add_request.amount,
-        claims.sub,
-        Uuid::new_v4(),
-    )
-    .fetch_one(&**pool)
-    .await;
-
-    match result {
-        Ok(record) => json_response(ApiResponse::success(BalanceResponse {
-            balance: record.balance.unwrap_or_default(),
-        })),
-        Err(_) => json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to update balance".to_string(),
-        )),
-    }
-}
+use crate::utils::{
+    auth::{AdminRole, Authenticated, BalanceRead, Scoped},
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct AddBalanceRequest {
+    amount: Decimal,
+    currency: Option<String>,
+    // User to credit, defaults to the caller
+    email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BalanceResponse {
+    pub currency: String,
+    // Ledger balance, including funds reserved by holds
+    pub balance: Decimal,
+    // What can be spent right now
+    pub available_balance: Decimal,
+}
+
+#[get("/balance")]
+pub async fn get_balance(
+    caller: Scoped<BalanceRead>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // One entry per currency wallet
+    let wallets = sqlx::query_as!(
+        BalanceResponse,
+        r#"
+        SELECT currency, balance, balance - held as "available_balance!"
+        FROM wallets WHERE user_id = $1 ORDER BY currency
+        "#,
+        caller.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match wallets {
+        Ok(wallets) => json_response(ApiResponse::success(wallets)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch balance".to_string(),
+        )),
+    }
+}
+
+// Credits a wallet without any funding behind it, for admin adjustments only.
+// Users add money through POST /balance/top-up
+#[post("/balance/add")]
+pub async fn add_amount(
+    claims: Authenticated<AdminRole>,
+    req: actix_web::HttpRequest,
+    add_request: web::Json<AddBalanceRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, claims.sub, &*add_request).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = credit(&pool, claims.sub, &add_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn credit(
+    pool: &sqlx::PgPool,
+    admin_id: Uuid,
+    add_request: &AddBalanceRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if add_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(add_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    let user_id = match &add_request.email {
+        Some(email) => {
+            match sqlx::query!("SELECT id FROM users WHERE email = $1", email)
+                .fetch_optional(pool)
+                .await
+            {
+                Ok(Some(user)) => user.id,
+                Ok(None) => {
+                    return json_response(ApiResponse::<MessageData>::error(
+                        404,
+                        "User not found".to_string(),
+                    ))
+                }
+                Err(_) => {
+                    return json_response(ApiResponse::<MessageData>::error(
+                        500,
+                        "Database error".to_string(),
+                    ))
+                }
+            }
+        }
+        None => admin_id,
+    };
+
+    // Update balance and create transaction record in a transaction
+    let result = sqlx::query!(
+        r#"
+        WITH updated_wallet AS (
+            INSERT INTO wallets (user_id, currency, balance)
+            VALUES ($2, $4, $1)
+            ON CONFLICT (user_id, currency)
+            DO UPDATE SET balance = wallets.balance + EXCLUDED.balance,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING balance, held
+        )
+        INSERT INTO transactions
+            (id, transfer_id, user_id, transaction_type, amount, currency, status, balance_after)
+        VALUES ($3, $3, $2, 'RECEIVED', $1, $4, 'SUCCESS', (SELECT balance FROM updated_wallet))
+        RETURNING (SELECT balance FROM updated_wallet),
+            (SELECT balance - held FROM updated_wallet) as available_balance
+        "#,
+        add_request.amount,
+        user_id,
+        Uuid::new_v4(),
+        currency
+    )
+    .fetch_one(pool)
+    .await;
+
+    match result {
+        Ok(record) => json_response(ApiResponse::success(BalanceResponse {
+            currency,
+            balance: record.balance.unwrap_or_default(),
+            available_balance: record.available_balance.unwrap_or_default(),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update balance".to_string(),
+        )),
+    }
+}
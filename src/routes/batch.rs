@@ -0,0 +1,240 @@
+use crate::engine::fees::{transfer_fee, FEE_ACCOUNT_ID};
+use crate::engine::SendTransactionRequest;
+use crate::engine::{
+    database_error, execute_transfer, lock_wallets, record_failed_transfer, retry::retry,
+    TransferError,
+};
+use crate::utils::{
+    auth::{PaymentsWrite, Scoped},
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::Acquire;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const MAX_BATCH_ITEMS: usize = 500;
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchTransferRequest {
+    // ATOMIC (default) or BEST_EFFORT
+    mode: Option<String>,
+    items: Vec<SendTransactionRequest>,
+}
+
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    index: usize,
+    email: String,
+    status: String,
+    transfer_id: Option<Uuid>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchTransferResponse {
+    mode: String,
+    succeeded: usize,
+    failed: usize,
+    results: Vec<BatchItemResult>,
+}
+
+#[post("/transactions/batch")]
+pub async fn send_batch(
+    caller: Scoped<PaymentsWrite>,
+    req: actix_web::HttpRequest,
+    batch_request: web::Json<BatchTransferRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, caller.sub, &*batch_request).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = batch_transfer(&pool, caller.sub, &batch_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+// Runs every item in one database transaction. ATOMIC batches stop and roll back at the
+// first failing item, BEST_EFFORT batches run each item in its own savepoint and report it
+async fn batch_transfer(
+    pool: &sqlx::PgPool,
+    sender_id: Uuid,
+    batch_request: &BatchTransferRequest,
+) -> HttpResponse {
+    let mode = batch_request
+        .mode
+        .as_deref()
+        .unwrap_or("ATOMIC")
+        .to_uppercase();
+    let atomic = match mode.as_str() {
+        "ATOMIC" => true,
+        "BEST_EFFORT" => false,
+        _ => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "mode must be ATOMIC or BEST_EFFORT".to_string(),
+            ))
+        }
+    };
+    let items = &batch_request.items;
+    if items.is_empty() || items.len() > MAX_BATCH_ITEMS {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            format!("A batch takes 1 to {} items", MAX_BATCH_ITEMS),
+        ));
+    }
+
+    match retry(async || try_batch(pool, sender_id, items, &mode, atomic).await).await {
+        Ok(response) => response,
+        Err(error) => error.to_response(),
+    }
+}
+
+// One attempt at the whole batch. A batch that loses a conflict is rolled back whole and
+// run again, only errors that running it again might fix are returned as errors
+async fn try_batch(
+    pool: &sqlx::PgPool,
+    sender_id: Uuid,
+    items: &[SendTransactionRequest],
+    mode: &str,
+    atomic: bool,
+) -> Result<HttpResponse, TransferError> {
+    // Start a transaction
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(database_error("Failed to start transaction"))?;
+
+    lock_batch(&mut tx, sender_id, items)
+        .await
+        .map_err(database_error("Database error"))?;
+
+    let mut results = Vec::with_capacity(items.len());
+    for (index, item) in items.iter().enumerate() {
+        let result = if atomic {
+            match execute_transfer(&mut tx, sender_id, item).await {
+                Ok(receipt) => Ok(receipt.transfer_id),
+                Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                    return Err(error)
+                }
+                Err(error) => {
+                    let (status_code, message) = error.status();
+                    return Ok(json_response(ApiResponse::<MessageData>::error(
+                        status_code,
+                        format!("Item {}: {}", index, message),
+                    )));
+                }
+            }
+        } else {
+            // A declined item only rolls back its own savepoint
+            let mut savepoint = tx.begin().await.map_err(database_error("Database error"))?;
+            match execute_transfer(&mut savepoint, sender_id, item).await {
+                Ok(receipt) => {
+                    savepoint
+                        .commit()
+                        .await
+                        .map_err(database_error("Database error"))?;
+                    Ok(receipt.transfer_id)
+                }
+                Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                    return Err(error)
+                }
+                Err(error) => {
+                    savepoint
+                        .rollback()
+                        .await
+                        .map_err(database_error("Database error"))?;
+                    record_failed_transfer(&mut *tx, sender_id, item, &error).await;
+                    Err(error.status().1)
+                }
+            }
+        };
+
+        results.push(match result {
+            Ok(transfer_id) => BatchItemResult {
+                index,
+                email: item.email.clone(),
+                status: "SUCCESS".to_string(),
+                transfer_id: Some(transfer_id),
+                error: None,
+            },
+            Err(message) => BatchItemResult {
+                index,
+                email: item.email.clone(),
+                status: "FAILURE".to_string(),
+                transfer_id: None,
+                error: Some(message.to_string()),
+            },
+        });
+    }
+
+    // Commit the transaction
+    tx.commit()
+        .await
+        .map_err(database_error("Failed to commit transaction"))?;
+
+    let failed = results
+        .iter()
+        .filter(|result| result.transfer_id.is_none())
+        .count();
+    Ok(json_response(ApiResponse::success(BatchTransferResponse {
+        mode: mode.to_string(),
+        succeeded: results.len() - failed,
+        failed,
+        results,
+    })))
+}
+
+// Locks every wallet the batch touches before the first transfer runs, in the same order
+// single transfers use, so a batch and concurrent transfers never wait on each other in a cycle
+async fn lock_batch(
+    conn: &mut sqlx::PgConnection,
+    sender_id: Uuid,
+    items: &[SendTransactionRequest],
+) -> Result<(), sqlx::Error> {
+    let emails: Vec<String> = items.iter().map(|item| item.email.clone()).collect();
+    let receivers: HashMap<String, Uuid> = sqlx::query!(
+        "SELECT id, email FROM users WHERE email = ANY($1) FOR KEY SHARE",
+        &emails
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|receiver| (receiver.email, receiver.id))
+    .collect();
+
+    // Items with an unknown receiver or currency fail on their own, they need no locks
+    let mut wallets = Vec::new();
+    for item in items {
+        let Ok(currency) = parse_currency(item.currency.as_deref()) else {
+            continue;
+        };
+        let receive_currency = match item.receive_currency.as_deref() {
+            Some(receive_currency) => match parse_currency(Some(receive_currency)) {
+                Ok(receive_currency) => receive_currency,
+                Err(_) => continue,
+            },
+            None => currency.clone(),
+        };
+        if let Some(receiver_id) = receivers.get(&item.email) {
+            wallets.push((*receiver_id, receive_currency));
+        }
+        // Fees are paid into the house wallet, which sorts ahead of every user's
+        let fee = transfer_fee(&mut *conn, sender_id, "SENT", &currency, item.amount).await?;
+        if fee > Decimal::new(0, 0) {
+            wallets.push((FEE_ACCOUNT_ID, currency.clone()));
+        }
+        wallets.push((sender_id, currency));
+    }
+    wallets.sort();
+    wallets.dedup();
+
+    lock_wallets(conn, &wallets).await
+}
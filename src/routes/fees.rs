@@ -0,0 +1,318 @@
+use crate::engine::fees::transfer_fee;
+use crate::utils::{
+    auth::{AdminRole, Authenticated, SupportRole},
+    currency::parse_currency,
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Transaction types a fee is charged on, transfers (holds included) and withdrawals
+const FEE_TRANSACTION_TYPES: &[&str] = &["SENT", "WITHDRAWAL"];
+
+#[derive(Deserialize)]
+pub struct FeeTier {
+    // Lower bound of the band, the first band starts at 0
+    #[serde(default)]
+    min_amount: Decimal,
+    #[serde(default)]
+    flat_fee: Decimal,
+    // Percent of the amount, 1.5 is 1.5%
+    #[serde(default)]
+    percentage: Decimal,
+    min_fee: Option<Decimal>,
+    max_fee: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+pub struct FeeScheduleRequest {
+    // Applies to every transaction type / tier when omitted
+    transaction_type: Option<String>,
+    user_tier: Option<String>,
+    currency: Option<String>,
+    // Replaces the schedule, no tiers removes it
+    tiers: Vec<FeeTier>,
+}
+
+#[derive(Serialize)]
+pub struct FeeRule {
+    id: Uuid,
+    transaction_type: Option<String>,
+    user_tier: Option<String>,
+    currency: String,
+    min_amount: Decimal,
+    flat_fee: Decimal,
+    percentage: Decimal,
+    min_fee: Option<Decimal>,
+    max_fee: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+pub struct SetTierRequest {
+    email: String,
+    tier: String,
+}
+
+#[derive(Serialize)]
+pub struct SetTierResponse {
+    email: String,
+    tier: String,
+}
+
+#[derive(Deserialize)]
+pub struct TransferQuoteRequest {
+    amount: Decimal,
+    currency: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TransferQuoteResponse {
+    amount: Decimal,
+    currency: String,
+    fee: Decimal,
+    // What leaves the sender's wallet
+    total: Decimal,
+}
+
+#[post("/transaction/quote")]
+pub async fn quote_transfer(
+    claims: Authenticated,
+    quote_request: web::Json<TransferQuoteRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Validate amount is positive
+    if quote_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+    let currency = match parse_currency(quote_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+    match transfer_fee(
+        &mut conn,
+        claims.sub,
+        "SENT",
+        &currency,
+        quote_request.amount,
+    )
+    .await
+    {
+        Ok(fee) => json_response(ApiResponse::success(TransferQuoteResponse {
+            amount: quote_request.amount,
+            currency,
+            fee,
+            total: quote_request.amount + fee,
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to compute fee".to_string(),
+        )),
+    }
+}
+
+#[get("/admin/fees")]
+pub async fn get_fee_rules(
+    _support: Authenticated<SupportRole>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let rules = sqlx::query_as!(
+        FeeRule,
+        r#"
+        SELECT id, transaction_type, user_tier, currency, min_amount, flat_fee, percentage,
+            min_fee, max_fee
+        FROM fee_rules
+        ORDER BY transaction_type NULLS FIRST, user_tier NULLS FIRST, currency, min_amount
+        "#
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match rules {
+        Ok(rules) => json_response(ApiResponse::success(rules)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch fee rules".to_string(),
+        )),
+    }
+}
+
+#[post("/admin/fees")]
+pub async fn set_fee_schedule(
+    _admin: Authenticated<AdminRole>,
+    schedule_request: web::Json<FeeScheduleRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let transaction_type = schedule_request
+        .transaction_type
+        .as_deref()
+        .map(str::to_uppercase);
+    if let Some(transaction_type) = &transaction_type {
+        if !FEE_TRANSACTION_TYPES.contains(&transaction_type.as_str()) {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Fees can only be set on SENT or WITHDRAWAL transactions".to_string(),
+            ));
+        }
+    }
+    let user_tier = schedule_request
+        .user_tier
+        .as_deref()
+        .map(|tier| tier.trim().to_uppercase());
+    let currency = match parse_currency(schedule_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    let zero = Decimal::new(0, 0);
+    // A schedule prices every amount, one left uncovered would quietly fall through to a
+    // less specific schedule
+    let tiers = &schedule_request.tiers;
+    if !tiers.is_empty() && !tiers.iter().any(|tier| tier.min_amount == zero) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "The first tier must start at 0".to_string(),
+        ));
+    }
+    for tier in &schedule_request.tiers {
+        let valid = tier.min_amount >= zero
+            && tier.flat_fee >= zero
+            && tier.percentage >= zero
+            && tier.percentage <= Decimal::ONE_HUNDRED
+            && tier.min_fee.is_none_or(|min_fee| min_fee >= zero)
+            && tier.max_fee.is_none_or(|max_fee| max_fee >= zero)
+            && !matches!((tier.min_fee, tier.max_fee), (Some(min), Some(max)) if min > max);
+        if !valid {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Invalid fee tier".to_string(),
+            ));
+        }
+    }
+
+    match store_schedule(
+        &pool,
+        transaction_type,
+        user_tier,
+        currency,
+        &schedule_request.tiers,
+    )
+    .await
+    {
+        Ok(rules) => json_response(ApiResponse::success(rules)),
+        // The unique band index rejects two tiers starting at the same amount
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Tiers must start at different amounts".to_string(),
+            ))
+        }
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to store fee schedule".to_string(),
+        )),
+    }
+}
+
+async fn store_schedule(
+    pool: &sqlx::PgPool,
+    transaction_type: Option<String>,
+    user_tier: Option<String>,
+    currency: String,
+    tiers: &[FeeTier],
+) -> Result<Vec<FeeRule>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM fee_rules
+        WHERE transaction_type IS NOT DISTINCT FROM $1 AND user_tier IS NOT DISTINCT FROM $2
+            AND currency = $3
+        "#,
+        transaction_type,
+        user_tier,
+        currency
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let mut rules = Vec::with_capacity(tiers.len());
+    for tier in tiers {
+        let rule = sqlx::query_as!(
+            FeeRule,
+            r#"
+            INSERT INTO fee_rules
+                (id, transaction_type, user_tier, currency, min_amount, flat_fee, percentage,
+                 min_fee, max_fee)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, transaction_type, user_tier, currency, min_amount, flat_fee,
+                percentage, min_fee, max_fee
+            "#,
+            Uuid::new_v4(),
+            transaction_type,
+            user_tier,
+            currency,
+            tier.min_amount,
+            tier.flat_fee,
+            tier.percentage,
+            tier.min_fee,
+            tier.max_fee
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        rules.push(rule);
+    }
+
+    tx.commit().await?;
+    Ok(rules)
+}
+
+#[post("/admin/users/tier")]
+pub async fn set_user_tier(
+    _admin: Authenticated<AdminRole>,
+    tier_request: web::Json<SetTierRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let tier = tier_request.tier.trim().to_uppercase();
+    if tier.is_empty() || tier.len() > 32 {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "tier must be 1 to 32 characters".to_string(),
+        ));
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE users SET tier = $2, updated_at = CURRENT_TIMESTAMP WHERE email = $1",
+        tier_request.email,
+        tier
+    )
+    .execute(&**pool)
+    .await;
+
+    match updated {
+        Ok(result) if result.rows_affected() == 0 => json_response(
+            ApiResponse::<MessageData>::error(404, "User not found".to_string()),
+        ),
+        Ok(_) => json_response(ApiResponse::success(SetTierResponse {
+            email: tier_request.email.clone(),
+            tier,
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update tier".to_string(),
+        )),
+    }
+}
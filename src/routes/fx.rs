@@ -0,0 +1,243 @@
+use crate::utils::{
+    auth::{AdminRole, Authenticated},
+    currency::{convert, parse_currency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{post, web, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::env;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct QuoteRequest {
+    from_currency: String,
+    to_currency: String,
+    amount: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct QuoteResponse {
+    quote_id: Uuid,
+    from_currency: String,
+    to_currency: String,
+    rate: Decimal,
+    amount: Option<Decimal>,
+    converted_amount: Option<Decimal>,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct FxRate {
+    base_currency: String,
+    quote_currency: String,
+    rate: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct SetRatesRequest {
+    rates: Vec<FxRate>,
+}
+
+#[derive(Serialize)]
+pub struct SetRatesResponse {
+    updated: usize,
+}
+
+// How long a quoted rate stays usable, configurable through FX_QUOTE_TTL_SECONDS
+fn quote_ttl_seconds() -> i32 {
+    env::var("FX_QUOTE_TTL_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(60)
+}
+
+#[post("/fx/quote")]
+pub async fn create_quote(
+    claims: Authenticated,
+    quote_request: web::Json<QuoteRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let (from_currency, to_currency) = match (
+        parse_currency(Some(&quote_request.from_currency)),
+        parse_currency(Some(&quote_request.to_currency)),
+    ) {
+        (Ok(from), Ok(to)) if from != to => (from, to),
+        (Ok(_), Ok(_)) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Currencies must differ".to_string(),
+            ))
+        }
+        (Err(msg), _) | (_, Err(msg)) => {
+            return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()))
+        }
+    };
+
+    // Validate amount is positive
+    if matches!(quote_request.amount, Some(amount) if amount <= Decimal::new(0, 0)) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    // Use the direct rate, or invert the opposite pair
+    let rate = sqlx::query!(
+        r#"
+        SELECT rate as "rate!" FROM (
+            SELECT rate, 0 AS priority FROM fx_rates
+            WHERE base_currency = $1 AND quote_currency = $2
+            UNION ALL
+            SELECT ROUND(1 / rate, 8), 1 FROM fx_rates
+            WHERE base_currency = $2 AND quote_currency = $1
+        ) rates
+        ORDER BY priority
+        LIMIT 1
+        "#,
+        from_currency,
+        to_currency
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    let rate = match rate {
+        Ok(Some(record)) => record.rate,
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "No rate for this currency pair".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    // Lock the rate in a quote that expires
+    let quote = sqlx::query!(
+        r#"
+        INSERT INTO fx_quotes (id, user_id, from_currency, to_currency, rate, expires_at)
+        VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))
+        RETURNING id, expires_at
+        "#,
+        Uuid::new_v4(),
+        claims.sub,
+        from_currency,
+        to_currency,
+        rate,
+        quote_ttl_seconds() as f64
+    )
+    .fetch_one(&**pool)
+    .await;
+
+    match quote {
+        Ok(quote) => json_response(ApiResponse::success(QuoteResponse {
+            quote_id: quote.id,
+            from_currency,
+            to_currency,
+            rate,
+            amount: quote_request.amount,
+            converted_amount: quote_request.amount.map(|amount| convert(amount, rate)),
+            expires_at: quote.expires_at,
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to create quote".to_string(),
+        )),
+    }
+}
+
+#[post("/admin/fx/rates")]
+pub async fn set_rates(
+    _admin: Authenticated<AdminRole>,
+    rates_request: web::Json<SetRatesRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let mut rates = Vec::with_capacity(rates_request.rates.len());
+    for rate in &rates_request.rates {
+        match (
+            parse_currency(Some(&rate.base_currency)),
+            parse_currency(Some(&rate.quote_currency)),
+        ) {
+            (Ok(base), Ok(quote)) if base != quote && rate.rate > Decimal::new(0, 0) => {
+                rates.push((base, quote, rate.rate))
+            }
+            _ => {
+                return json_response(ApiResponse::<MessageData>::error(
+                    400,
+                    "Invalid rate".to_string(),
+                ))
+            }
+        }
+    }
+
+    match store_rates(&pool, &rates).await {
+        Ok(()) => json_response(ApiResponse::success(SetRatesResponse {
+            updated: rates.len(),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to store rates".to_string(),
+        )),
+    }
+}
+
+async fn store_rates(
+    pool: &sqlx::PgPool,
+    rates: &[(String, String, Decimal)],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for (base, quote, rate) in rates {
+        sqlx::query!(
+            r#"
+            INSERT INTO fx_rates (base_currency, quote_currency, rate)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (base_currency, quote_currency)
+            DO UPDATE SET rate = EXCLUDED.rate, updated_at = CURRENT_TIMESTAMP
+            "#,
+            base,
+            quote,
+            rate
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+// Load `base_currency,quote_currency,rate` lines from the CSV file at FX_RATES_FILE
+pub async fn load_rates_from_csv(pool: &sqlx::PgPool, path: &str) -> Result<usize, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let mut rates = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        // Skip the header and blank lines
+        if line.is_empty() || line_number == 0 && line.starts_with("base_currency") {
+            continue;
+        }
+        let invalid = || format!("Invalid rate on line {}", line_number + 1);
+        let mut fields = line.split(',').map(str::trim);
+        let (Some(base), Some(quote), Some(rate), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let base = parse_currency(Some(base)).map_err(|_| invalid())?;
+        let quote = parse_currency(Some(quote)).map_err(|_| invalid())?;
+        let rate: Decimal = rate.parse().map_err(|_| invalid())?;
+        if base == quote || rate <= Decimal::new(0, 0) {
+            return Err(invalid());
+        }
+        rates.push((base, quote, rate));
+    }
+
+    store_rates(pool, &rates).await.map_err(|e| e.to_string())?;
+    Ok(rates.len())
+}
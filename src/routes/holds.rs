@@ -0,0 +1,564 @@
+use crate::engine::fees::transfer_fee;
+use crate::engine::limits::{exceeded_limit, limit_message};
+use crate::engine::{capture_transfer, database_error, lock_wallets, retry::retry, TransferError};
+use crate::engine::{validate_details, SendTransactionRequest};
+use crate::routes::scheduled::poll_interval;
+use crate::utils::{
+    auth::Authenticated,
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct HoldRequest {
+    amount: Decimal,
+    // The merchant allowed to capture the hold
+    email: String,
+    currency: Option<String>,
+    memo: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CaptureRequest {
+    // Defaults to the full hold, the rest is released
+    amount: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct HoldResponse {
+    id: Uuid,
+    payer_email: String,
+    merchant_email: String,
+    amount: Decimal,
+    // Reserved on top of the amount, what a capture pays at most
+    fee: Decimal,
+    currency: String,
+    memo: Option<String>,
+    status: String,
+    captured_amount: Option<Decimal>,
+    transfer_id: Option<Uuid>,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+}
+
+// How long an uncaptured hold keeps funds reserved, configurable through HOLD_TTL_HOURS
+fn hold_ttl_hours() -> i32 {
+    env::var("HOLD_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(168)
+}
+
+async fn find_hold(pool: &sqlx::PgPool, hold_id: Uuid) -> Result<HoldResponse, sqlx::Error> {
+    sqlx::query_as!(
+        HoldResponse,
+        r#"
+        SELECT h.id, payer.email as payer_email, merchant.email as merchant_email, h.amount,
+            h.fee, h.currency, h.memo, h.status::text as "status!", h.captured_amount, h.transfer_id,
+            h.expires_at, h.created_at, h.updated_at
+        FROM holds h
+        JOIN users payer ON payer.id = h.user_id
+        JOIN users merchant ON merchant.id = h.merchant_id
+        WHERE h.id = $1
+        "#,
+        hold_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+fn hold_response(result: Result<HoldResponse, sqlx::Error>) -> HttpResponse {
+    match result {
+        Ok(hold) => json_response(ApiResponse::success(hold)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch hold".to_string(),
+        )),
+    }
+}
+
+#[post("/holds")]
+pub async fn create_hold(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    hold_request: web::Json<HoldRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, claims.sub, &*hold_request).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = authorize(&pool, claims.sub, &hold_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn authorize(pool: &sqlx::PgPool, user_id: Uuid, hold_request: &HoldRequest) -> HttpResponse {
+    // Validate amount is positive
+    if hold_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(hold_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    if let Err(msg) = validate_details(hold_request.memo.as_deref(), None) {
+        return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()));
+    }
+
+    let merchant = sqlx::query!("SELECT id FROM users WHERE email = $1", hold_request.email)
+        .fetch_optional(pool)
+        .await;
+
+    let merchant_id = match merchant {
+        Ok(Some(merchant)) if merchant.id == user_id => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Cannot place a hold for yourself".to_string(),
+            ))
+        }
+        Ok(Some(merchant)) => merchant.id,
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "Merchant not found".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    // Start a transaction
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to start transaction".to_string(),
+            ))
+        }
+    };
+
+    // The fee is reserved along with the amount, so capturing the hold can always pay it
+    let fee = match transfer_fee(&mut tx, user_id, "SENT", &currency, hold_request.amount).await {
+        Ok(fee) => fee,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    // Reserve the funds, only out of the available balance
+    let reserved = sqlx::query!(
+        r#"
+        UPDATE wallets SET held = held + $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3 AND balance - held >= $1
+        "#,
+        hold_request.amount + fee,
+        user_id,
+        currency
+    )
+    .execute(&mut *tx)
+    .await;
+
+    match reserved {
+        Ok(result) if result.rows_affected() == 1 => {}
+        Ok(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Insufficient balance".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to reserve funds".to_string(),
+            ))
+        }
+    }
+
+    // Limits are checked once, when the hold is placed, under the lock the reservation took
+    match exceeded_limit(&mut tx, user_id, &currency, hold_request.amount).await {
+        Ok(None) => {}
+        Ok(Some(limit)) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                403,
+                limit_message(limit).to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    }
+
+    // The response comes from the insert, a failed read after the commit would report a
+    // placed hold as an error and a retry with the same Idempotency-Key would place another
+    let created = sqlx::query_as!(
+        HoldResponse,
+        r#"
+        WITH hold AS (
+            INSERT INTO holds (id, user_id, merchant_id, amount, fee, currency, memo, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW() + make_interval(hours => $8))
+            RETURNING *
+        )
+        SELECT h.id as "id!", payer.email as payer_email, merchant.email as merchant_email,
+            h.amount as "amount!", h.fee as "fee!", h.currency as "currency!", h.memo,
+            h.status::text as "status!", h.captured_amount, h.transfer_id,
+            h.expires_at as "expires_at!", h.created_at as "created_at!",
+            h.updated_at as "updated_at!"
+        FROM hold h
+        JOIN users payer ON payer.id = h.user_id
+        JOIN users merchant ON merchant.id = h.merchant_id
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        merchant_id,
+        hold_request.amount,
+        fee,
+        currency,
+        hold_request.memo,
+        hold_ttl_hours()
+    )
+    .fetch_one(&mut *tx)
+    .await;
+
+    let created = match created {
+        Ok(created) => created,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to create hold".to_string(),
+            ))
+        }
+    };
+
+    // Commit the transaction
+    if tx.commit().await.is_err() {
+        return json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to commit transaction".to_string(),
+        ));
+    }
+
+    json_response(ApiResponse::success(created))
+}
+
+#[get("/holds")]
+pub async fn get_holds(claims: Authenticated, pool: web::Data<sqlx::PgPool>) -> impl Responder {
+    // Holds the caller placed or can capture, newest first
+    let holds = sqlx::query_as!(
+        HoldResponse,
+        r#"
+        SELECT h.id, payer.email as payer_email, merchant.email as merchant_email, h.amount,
+            h.fee, h.currency, h.memo, h.status::text as "status!", h.captured_amount, h.transfer_id,
+            h.expires_at, h.created_at, h.updated_at
+        FROM holds h
+        JOIN users payer ON payer.id = h.user_id
+        JOIN users merchant ON merchant.id = h.merchant_id
+        WHERE h.user_id = $1 OR h.merchant_id = $1
+        ORDER BY h.created_at DESC, h.id DESC
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match holds {
+        Ok(holds) => json_response(ApiResponse::success(holds)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch holds".to_string(),
+        )),
+    }
+}
+
+#[post("/holds/{id}/capture")]
+pub async fn capture_hold(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    capture_request: Option<web::Json<CaptureRequest>>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let hold_id = path.into_inner();
+    let capture_request = capture_request
+        .map(|capture_request| capture_request.into_inner())
+        .unwrap_or(CaptureRequest { amount: None });
+
+    // Validate amount is positive
+    if matches!(capture_request.amount, Some(amount) if amount <= Decimal::new(0, 0)) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    // Replay the original response if this request was already handled
+    let idempotency_key =
+        match idempotency::begin(&req, &pool, claims.sub, &(hold_id, &capture_request)).await {
+            Idempotency::Proceed(key) => key,
+            Idempotency::Done(response) => return response,
+        };
+
+    let response = settle(&pool, claims.sub, hold_id, capture_request.amount).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+#[post("/holds/{id}/void")]
+pub async fn void_hold(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    settle(
+        &pool,
+        claims.sub,
+        path.into_inner(),
+        Some(Decimal::new(0, 0)),
+    )
+    .await
+}
+
+// Captures `amount` of a hold (all of it when None) or voids it when amount is zero.
+// Either way the reservation is released, only the merchant can settle a hold
+async fn settle(
+    pool: &sqlx::PgPool,
+    merchant_id: Uuid,
+    hold_id: Uuid,
+    amount: Option<Decimal>,
+) -> HttpResponse {
+    match retry(async || try_settle(pool, merchant_id, hold_id, amount).await).await {
+        Ok(response) => response,
+        Err(error) => error.to_response(),
+    }
+}
+
+// One attempt at settling a hold, only errors that running it again might fix are
+// returned as errors
+async fn try_settle(
+    pool: &sqlx::PgPool,
+    merchant_id: Uuid,
+    hold_id: Uuid,
+    amount: Option<Decimal>,
+) -> Result<HttpResponse, TransferError> {
+    // Start a transaction
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(database_error("Failed to start transaction"))?;
+
+    // Lock the hold so it is settled once
+    let hold = sqlx::query!(
+        r#"
+        SELECT h.user_id, h.amount, h.fee, h.currency, h.memo, h.status::text as "status!",
+            h.expires_at <= NOW() as "expired!", merchant.email
+        FROM holds h
+        JOIN users merchant ON merchant.id = h.merchant_id
+        WHERE h.id = $1 AND h.merchant_id = $2
+        FOR UPDATE OF h
+        "#,
+        hold_id,
+        merchant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(database_error("Database error"))?;
+
+    let Some(hold) = hold else {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Hold not found".to_string(),
+        )));
+    };
+
+    if hold.status != "ACTIVE" {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Hold is {}", hold.status),
+        )));
+    }
+    if hold.expired {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            409,
+            "Hold is EXPIRED".to_string(),
+        )));
+    }
+
+    let amount = amount.unwrap_or(hold.amount);
+    if amount > hold.amount {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Capture exceeds the held amount".to_string(),
+        )));
+    }
+
+    lock_wallets(
+        &mut tx,
+        &[
+            (hold.user_id, hold.currency.clone()),
+            (merchant_id, hold.currency.clone()),
+        ],
+    )
+    .await
+    .map_err(database_error("Database error"))?;
+
+    // Release the reservation, the captured part is sent right after
+    sqlx::query!(
+        r#"
+        UPDATE wallets SET held = held - $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        "#,
+        hold.amount + hold.fee,
+        hold.user_id,
+        hold.currency
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(database_error("Failed to release funds"))?;
+
+    let transfer_id = if amount > Decimal::new(0, 0) {
+        let send_request = SendTransactionRequest {
+            amount,
+            email: hold.email,
+            memo: hold.memo,
+            metadata: None,
+            currency: Some(hold.currency),
+            receive_currency: None,
+            quote_id: None,
+        };
+        match capture_transfer(&mut tx, hold.user_id, &send_request, hold.fee).await {
+            Ok(receipt) => Some(receipt.transfer_id),
+            Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                return Err(error)
+            }
+            Err(error) => return Ok(error.to_response()),
+        }
+    } else {
+        None
+    };
+
+    sqlx::query!(
+        r#"
+        UPDATE holds
+        SET status = $2::text::hold_status, captured_amount = $3, transfer_id = $4,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+        hold_id,
+        if transfer_id.is_some() {
+            "CAPTURED"
+        } else {
+            "VOIDED"
+        },
+        transfer_id.map(|_| amount),
+        transfer_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(database_error("Failed to update hold"))?;
+
+    // Commit the transaction
+    tx.commit()
+        .await
+        .map_err(database_error("Failed to commit transaction"))?;
+
+    Ok(hold_response(find_hold(pool, hold_id).await))
+}
+
+// Releases holds nobody captured in time, returns how many expired
+pub async fn expire_holds(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Holds are locked before their wallets, the same order settling one takes
+    let expired = sqlx::query!(
+        r#"
+        SELECT id, user_id, currency FROM holds
+        WHERE status = 'ACTIVE' AND expires_at <= NOW()
+        ORDER BY id
+        FOR UPDATE
+        "#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let hold_ids: Vec<Uuid> = expired.iter().map(|hold| hold.id).collect();
+    let mut wallets: Vec<(Uuid, String)> = expired
+        .into_iter()
+        .map(|hold| (hold.user_id, hold.currency))
+        .collect();
+    wallets.sort();
+    wallets.dedup();
+    lock_wallets(&mut tx, &wallets).await?;
+
+    sqlx::query!(
+        r#"
+        WITH expired AS (
+            UPDATE holds SET status = 'EXPIRED', updated_at = CURRENT_TIMESTAMP
+            WHERE id = ANY($1)
+            RETURNING user_id, currency, amount + fee as amount
+        )
+        UPDATE wallets w
+        SET held = w.held - e.amount, updated_at = CURRENT_TIMESTAMP
+        FROM (
+            SELECT user_id, currency, SUM(amount) as amount
+            FROM expired
+            GROUP BY user_id, currency
+        ) e
+        WHERE w.user_id = e.user_id AND w.currency = e.currency
+        "#,
+        &hold_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(hold_ids.len() as u64)
+}
+
+pub async fn process_hold_expiry() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = expire_holds(&pool).await {
+            eprintln!("Failed to expire holds: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
@@ -0,0 +1,11 @@
+use crate::utils::keys::JwtKeys;
+use actix_web::{get, web, HttpResponse, Responder};
+
+// Public keys access tokens are signed with, served as a plain JWK Set since that is
+// what JWT libraries fetch. Caches may hold it for a while, rotation keeps old keys around
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(keys: web::Data<JwtKeys>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(keys.jwks())
+}
@@ -0,0 +1,297 @@
+use crate::engine::limits::{sent_usage, user_limits, TransferLimits};
+use crate::utils::{
+    auth::{AdminRole, Authenticated, SupportRole},
+    currency::parse_currency,
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct LimitsQuery {
+    currency: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AmountLimitUsage {
+    limit: Option<Decimal>,
+    used: Decimal,
+    // None when there is no cap
+    remaining: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct CountLimitUsage {
+    limit: Option<i32>,
+    used: i64,
+    remaining: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct LimitsResponse {
+    currency: String,
+    tier: String,
+    // Set by an admin for this user instead of the tier's limits
+    overridden: bool,
+    per_transaction_amount: Option<Decimal>,
+    daily_amount: AmountLimitUsage,
+    monthly_amount: AmountLimitUsage,
+    daily_count: CountLimitUsage,
+    monthly_count: CountLimitUsage,
+}
+
+#[derive(Serialize)]
+pub struct TierLimitsResponse {
+    tier: String,
+    per_transaction_amount: Option<Decimal>,
+    daily_amount: Option<Decimal>,
+    monthly_amount: Option<Decimal>,
+    daily_count: Option<i32>,
+    monthly_count: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct SetTierLimitsRequest {
+    tier: String,
+    #[serde(flatten)]
+    limits: TransferLimits,
+}
+
+#[derive(Deserialize)]
+pub struct SetUserLimitsRequest {
+    email: String,
+    // Omitted puts the user back on their tier's limits
+    limits: Option<TransferLimits>,
+}
+
+fn amount_usage(limit: Option<Decimal>, used: Decimal) -> AmountLimitUsage {
+    AmountLimitUsage {
+        limit,
+        used,
+        remaining: limit.map(|limit| (limit - used).max(Decimal::new(0, 0))),
+    }
+}
+
+fn count_usage(limit: Option<i32>, used: i64) -> CountLimitUsage {
+    CountLimitUsage {
+        limit,
+        used,
+        remaining: limit.map(|limit| (i64::from(limit) - used).max(0)),
+    }
+}
+
+#[get("/limits")]
+pub async fn get_limits(
+    claims: Authenticated,
+    query: web::Query<LimitsQuery>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let currency = match parse_currency(query.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+    let limits = user_limits(&mut conn, claims.sub).await;
+    let usage = sent_usage(&mut conn, claims.sub, &currency).await;
+
+    match (limits, usage) {
+        (Ok(user), Ok(usage)) => json_response(ApiResponse::success(LimitsResponse {
+            currency,
+            tier: user.tier,
+            overridden: user.overridden,
+            per_transaction_amount: user.limits.per_transaction_amount,
+            daily_amount: amount_usage(user.limits.daily_amount, usage.daily_amount),
+            monthly_amount: amount_usage(user.limits.monthly_amount, usage.monthly_amount),
+            daily_count: count_usage(user.limits.daily_count, usage.daily_count),
+            monthly_count: count_usage(user.limits.monthly_count, usage.monthly_count),
+        })),
+        _ => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch limits".to_string(),
+        )),
+    }
+}
+
+fn valid_limits(limits: &TransferLimits) -> bool {
+    let zero = Decimal::new(0, 0);
+    limits
+        .per_transaction_amount
+        .is_none_or(|limit| limit >= zero)
+        && limits.daily_amount.is_none_or(|limit| limit >= zero)
+        && limits.monthly_amount.is_none_or(|limit| limit >= zero)
+        && limits.daily_count.is_none_or(|limit| limit >= 0)
+        && limits.monthly_count.is_none_or(|limit| limit >= 0)
+}
+
+#[get("/admin/limits")]
+pub async fn get_tier_limits(
+    _support: Authenticated<SupportRole>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let tiers = sqlx::query_as!(
+        TierLimitsResponse,
+        r#"
+        SELECT tier, per_transaction_amount, daily_amount, monthly_amount, daily_count,
+            monthly_count
+        FROM tier_limits
+        ORDER BY tier
+        "#
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match tiers {
+        Ok(tiers) => json_response(ApiResponse::success(tiers)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch limits".to_string(),
+        )),
+    }
+}
+
+#[post("/admin/limits/tier")]
+pub async fn set_tier_limits(
+    _admin: Authenticated<AdminRole>,
+    limits_request: web::Json<SetTierLimitsRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let tier = limits_request.tier.trim().to_uppercase();
+    if tier.is_empty() || tier.len() > 32 {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "tier must be 1 to 32 characters".to_string(),
+        ));
+    }
+    let limits = &limits_request.limits;
+    if !valid_limits(limits) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Limits can't be negative".to_string(),
+        ));
+    }
+
+    let stored = sqlx::query!(
+        r#"
+        INSERT INTO tier_limits
+            (tier, per_transaction_amount, daily_amount, monthly_amount, daily_count,
+             monthly_count)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (tier) DO UPDATE SET
+            per_transaction_amount = EXCLUDED.per_transaction_amount,
+            daily_amount = EXCLUDED.daily_amount,
+            monthly_amount = EXCLUDED.monthly_amount,
+            daily_count = EXCLUDED.daily_count,
+            monthly_count = EXCLUDED.monthly_count,
+            updated_at = CURRENT_TIMESTAMP
+        "#,
+        tier,
+        limits.per_transaction_amount,
+        limits.daily_amount,
+        limits.monthly_amount,
+        limits.daily_count,
+        limits.monthly_count
+    )
+    .execute(&**pool)
+    .await;
+
+    match stored {
+        Ok(_) => json_response(ApiResponse::success(MessageData {
+            message: format!("Limits for tier {} updated", tier),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to store limits".to_string(),
+        )),
+    }
+}
+
+#[post("/admin/limits/user")]
+pub async fn set_user_limits(
+    _admin: Authenticated<AdminRole>,
+    limits_request: web::Json<SetUserLimitsRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    if limits_request
+        .limits
+        .as_ref()
+        .is_some_and(|limits| !valid_limits(limits))
+    {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Limits can't be negative".to_string(),
+        ));
+    }
+
+    let user = match sqlx::query!(
+        "SELECT id FROM users WHERE email = $1",
+        limits_request.email
+    )
+    .fetch_optional(&**pool)
+    .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "User not found".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    let stored = match &limits_request.limits {
+        Some(limits) => sqlx::query!(
+            r#"
+            INSERT INTO user_limits
+                (user_id, per_transaction_amount, daily_amount, monthly_amount, daily_count,
+                 monthly_count)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id) DO UPDATE SET
+                per_transaction_amount = EXCLUDED.per_transaction_amount,
+                daily_amount = EXCLUDED.daily_amount,
+                monthly_amount = EXCLUDED.monthly_amount,
+                daily_count = EXCLUDED.daily_count,
+                monthly_count = EXCLUDED.monthly_count,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            user.id,
+            limits.per_transaction_amount,
+            limits.daily_amount,
+            limits.monthly_amount,
+            limits.daily_count,
+            limits.monthly_count
+        )
+        .execute(&**pool)
+        .await
+        .map(|_| ()),
+        None => sqlx::query!("DELETE FROM user_limits WHERE user_id = $1", user.id)
+            .execute(&**pool)
+            .await
+            .map(|_| ()),
+    };
+
+    match stored {
+        Ok(()) => json_response(ApiResponse::success(MessageData {
+            message: format!("Limits for {} updated", limits_request.email),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to store limits".to_string(),
+        )),
+    }
+}
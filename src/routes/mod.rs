@@ -1,5 +1,19 @@
-pub mod balance;
-pub mod health;
-pub mod merchant;
-pub mod transactions;
-pub mod user;
+pub mod api_keys;
+pub mod balance;
+pub mod batch;
+pub mod fees;
+pub mod fx;
+pub mod health;
+pub mod holds;
+pub mod jwks;
+pub mod limits;
+pub mod merchant;
+pub mod payment_requests;
+pub mod reconciliation;
+pub mod scheduled;
+pub mod standing_orders;
+pub mod statements;
+pub mod top_ups;
+pub mod transactions;
+pub mod user;
+pub mod withdrawals;
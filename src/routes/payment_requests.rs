@@ -0,0 +1,466 @@
+use crate::engine::{
+    database_error, execute_transfer, record_failed_transfer, retry::retry, TransferError,
+};
+use crate::engine::{validate_details, SendTransactionRequest};
+use crate::routes::scheduled::poll_interval;
+use crate::utils::{
+    auth::{PaymentsRead, PaymentsWrite, Scoped},
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentRequestRequest {
+    amount: Decimal,
+    // The user asked to pay
+    email: String,
+    currency: Option<String>,
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PaymentRequestsQuery {
+    // incoming (to pay, the default) or outgoing (sent by the caller)
+    direction: Option<String>,
+    status: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PaymentRequestResponse {
+    id: Uuid,
+    requester_email: String,
+    payer_email: String,
+    amount: Decimal,
+    currency: String,
+    note: Option<String>,
+    status: String,
+    transfer_id: Option<Uuid>,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+}
+
+const PAYMENT_REQUEST_STATUSES: [&str; 5] = ["PENDING", "PAID", "DECLINED", "EXPIRED", "CANCELLED"];
+
+// How long a request can be paid, configurable through PAYMENT_REQUEST_TTL_HOURS
+fn request_ttl_hours() -> i32 {
+    env::var("PAYMENT_REQUEST_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(168)
+}
+
+async fn find_payment_request(
+    pool: &sqlx::PgPool,
+    request_id: Uuid,
+) -> Result<PaymentRequestResponse, sqlx::Error> {
+    sqlx::query_as!(
+        PaymentRequestResponse,
+        r#"
+        SELECT p.id, requester.email as requester_email, payer.email as payer_email, p.amount,
+            p.currency, p.note, p.status::text as "status!", p.transfer_id, p.expires_at,
+            p.created_at, p.updated_at
+        FROM payment_requests p
+        JOIN users requester ON requester.id = p.requester_id
+        JOIN users payer ON payer.id = p.payer_id
+        WHERE p.id = $1
+        "#,
+        request_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+fn payment_request_response(result: Result<PaymentRequestResponse, sqlx::Error>) -> HttpResponse {
+    match result {
+        Ok(payment_request) => json_response(ApiResponse::success(payment_request)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch payment request".to_string(),
+        )),
+    }
+}
+
+#[post("/payment/requests")]
+pub async fn create_payment_request(
+    caller: Scoped<PaymentsWrite>,
+    payment_request: web::Json<PaymentRequestRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Validate amount is positive
+    if payment_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(payment_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    if let Err(msg) = validate_details(payment_request.note.as_deref(), None) {
+        return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()));
+    }
+
+    let payer = sqlx::query!(
+        "SELECT id FROM users WHERE email = $1",
+        payment_request.email
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    let payer_id = match payer {
+        Ok(Some(payer)) if payer.id == caller.sub => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Cannot request money from yourself".to_string(),
+            ))
+        }
+        Ok(Some(payer)) => payer.id,
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "Payer not found".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    let created = sqlx::query!(
+        r#"
+        INSERT INTO payment_requests
+            (id, requester_id, payer_id, amount, currency, note, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW() + make_interval(hours => $7))
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
+        caller.sub,
+        payer_id,
+        payment_request.amount,
+        currency,
+        payment_request.note,
+        request_ttl_hours()
+    )
+    .fetch_one(&**pool)
+    .await;
+
+    match created {
+        Ok(created) => payment_request_response(find_payment_request(&pool, created.id).await),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to create payment request".to_string(),
+        )),
+    }
+}
+
+#[get("/payment/requests")]
+pub async fn get_payment_requests(
+    caller: Scoped<PaymentsRead>,
+    query: web::Query<PaymentRequestsQuery>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let incoming = match query.direction.as_deref() {
+        None | Some("incoming") => true,
+        Some("outgoing") => false,
+        Some(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "direction must be incoming or outgoing".to_string(),
+            ))
+        }
+    };
+
+    let status = query.status.as_deref().map(str::to_uppercase);
+    if matches!(&status, Some(status) if !PAYMENT_REQUEST_STATUSES.contains(&status.as_str())) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Invalid status".to_string(),
+        ));
+    }
+
+    // Newest first, filtered on the caller's side of the request
+    let payment_requests = sqlx::query_as!(
+        PaymentRequestResponse,
+        r#"
+        SELECT p.id, requester.email as requester_email, payer.email as payer_email, p.amount,
+            p.currency, p.note, p.status::text as "status!", p.transfer_id, p.expires_at,
+            p.created_at, p.updated_at
+        FROM payment_requests p
+        JOIN users requester ON requester.id = p.requester_id
+        JOIN users payer ON payer.id = p.payer_id
+        WHERE (CASE WHEN $2 THEN p.payer_id ELSE p.requester_id END) = $1
+            AND ($3::text IS NULL OR p.status::text = $3)
+        ORDER BY p.created_at DESC, p.id DESC
+        "#,
+        caller.sub,
+        incoming,
+        status
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match payment_requests {
+        Ok(payment_requests) => json_response(ApiResponse::success(payment_requests)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch payment requests".to_string(),
+        )),
+    }
+}
+
+#[post("/payment/requests/{id}/approve")]
+pub async fn approve_payment_request(
+    caller: Scoped<PaymentsWrite>,
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let request_id = path.into_inner();
+
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, caller.sub, &request_id).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = approve(&pool, caller.sub, request_id).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn approve(pool: &sqlx::PgPool, payer_id: Uuid, request_id: Uuid) -> HttpResponse {
+    match retry(async || try_approve(pool, payer_id, request_id).await).await {
+        Ok(response) => response,
+        Err(error) => error.to_response(),
+    }
+}
+
+// One attempt at paying a request, only errors that running it again might fix are
+// returned as errors
+async fn try_approve(
+    pool: &sqlx::PgPool,
+    payer_id: Uuid,
+    request_id: Uuid,
+) -> Result<HttpResponse, TransferError> {
+    // Start a transaction
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(database_error("Failed to start transaction"))?;
+
+    // Lock the request so it is paid at most once
+    let payment_request = sqlx::query!(
+        r#"
+        SELECT p.amount, p.currency, p.note, p.status::text as "status!",
+            p.expires_at <= NOW() as "expired!", requester.email
+        FROM payment_requests p
+        JOIN users requester ON requester.id = p.requester_id
+        WHERE p.id = $1 AND p.payer_id = $2
+        FOR UPDATE OF p
+        "#,
+        request_id,
+        payer_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(database_error("Database error"))?;
+
+    let Some(payment_request) = payment_request else {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Payment request not found".to_string(),
+        )));
+    };
+
+    if payment_request.status != "PENDING" {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Payment request is {}", payment_request.status),
+        )));
+    }
+    if payment_request.expired {
+        drop(tx);
+        let _ = expire_payment_requests(pool).await;
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            409,
+            "Payment request is EXPIRED".to_string(),
+        )));
+    }
+
+    let send_request = SendTransactionRequest {
+        amount: payment_request.amount,
+        email: payment_request.email,
+        memo: payment_request.note,
+        metadata: None,
+        currency: Some(payment_request.currency),
+        receive_currency: None,
+        quote_id: None,
+    };
+
+    let receipt = match execute_transfer(&mut tx, payer_id, &send_request).await {
+        Ok(receipt) => receipt,
+        Err(error @ (TransferError::Contention | TransferError::Database(_))) => return Err(error),
+        Err(error) => {
+            // A declined payment leaves the request pending
+            drop(tx);
+            record_failed_transfer(pool, payer_id, &send_request, &error).await;
+            return Ok(error.to_response());
+        }
+    };
+
+    sqlx::query!(
+        r#"
+        UPDATE payment_requests
+        SET status = 'PAID', transfer_id = $2, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+        request_id,
+        receipt.transfer_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(database_error("Failed to update payment request"))?;
+
+    // Commit the transaction
+    tx.commit()
+        .await
+        .map_err(database_error("Failed to commit transaction"))?;
+
+    Ok(payment_request_response(
+        find_payment_request(pool, request_id).await,
+    ))
+}
+
+#[post("/payment/requests/{id}/decline")]
+pub async fn decline_payment_request(
+    caller: Scoped<PaymentsWrite>,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    close(&pool, path.into_inner(), caller.sub, true, "DECLINED").await
+}
+
+#[post("/payment/requests/{id}/cancel")]
+pub async fn cancel_payment_request(
+    caller: Scoped<PaymentsWrite>,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    close(&pool, path.into_inner(), caller.sub, false, "CANCELLED").await
+}
+
+// The payer declines a request, the requester cancels it
+async fn close(
+    pool: &sqlx::PgPool,
+    request_id: Uuid,
+    user_id: Uuid,
+    as_payer: bool,
+    status: &str,
+) -> HttpResponse {
+    let _ = expire_payment_requests(pool).await;
+
+    let closed = sqlx::query!(
+        r#"
+        UPDATE payment_requests
+        SET status = $4::text::payment_request_status, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND (CASE WHEN $3 THEN payer_id ELSE requester_id END) = $2
+            AND status = 'PENDING'
+        RETURNING id
+        "#,
+        request_id,
+        user_id,
+        as_payer,
+        status
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match closed {
+        Ok(Some(_)) => {
+            return payment_request_response(find_payment_request(pool, request_id).await)
+        }
+        Ok(None) => {}
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to update payment request".to_string(),
+            ))
+        }
+    }
+
+    // Tell a missing request apart from one that is no longer pending
+    let existing = sqlx::query!(
+        r#"
+        SELECT status::text as "status!" FROM payment_requests
+        WHERE id = $1 AND (CASE WHEN $3 THEN payer_id ELSE requester_id END) = $2
+        "#,
+        request_id,
+        user_id,
+        as_payer
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match existing {
+        Ok(Some(existing)) => json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Payment request is {}", existing.status),
+        )),
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Payment request not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        )),
+    }
+}
+
+// Moves pending requests past their expiry to EXPIRED, which also sends their webhooks
+pub async fn expire_payment_requests(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+    let expired = sqlx::query!(
+        r#"
+        UPDATE payment_requests SET status = 'EXPIRED', updated_at = CURRENT_TIMESTAMP
+        WHERE status = 'PENDING' AND expires_at <= NOW()
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(expired.rows_affected())
+}
+
+pub async fn process_payment_request_expiry() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = expire_payment_requests(&pool).await {
+            eprintln!("Failed to expire payment requests: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
@@ -0,0 +1,114 @@
+use crate::utils::{
+    auth::{Authenticated, SupportRole},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, web, Responder};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct WalletDrift {
+    pub user_id: Uuid,
+    pub email: String,
+    pub currency: String,
+    pub wallet_balance: Decimal,
+    // Sum of the wallet's settled ledger entries
+    pub ledger_balance: Decimal,
+    // wallet_balance - ledger_balance
+    pub drift: Decimal,
+    // Running balance on the wallet's latest entry
+    pub last_balance_after: Option<Decimal>,
+    // Entries whose balance_after doesn't follow from the entry before
+    pub broken_entries: i64,
+}
+
+#[derive(Serialize)]
+pub struct ReconciliationReport {
+    pub wallets_checked: usize,
+    pub drifts: Vec<WalletDrift>,
+}
+
+// Checks every wallet against its ledger entries. Declined (FAILURE) entries never moved
+// money, REVERSED ones did and were offset by their refunds
+pub async fn reconcile(pool: &sqlx::PgPool) -> Result<ReconciliationReport, sqlx::Error> {
+    let wallets = sqlx::query!(
+        r#"
+        WITH entries AS (
+            SELECT user_id, currency, balance_after, ledger_seq,
+                CASE WHEN transaction_type IN
+                        ('SENT', 'REFUND_SENT', 'WITHDRAWAL', 'FEE', 'FEE_REFUND_SENT')
+                    THEN -amount ELSE amount END AS change
+            FROM transactions
+            WHERE status <> 'FAILURE'
+        ),
+        chained AS (
+            SELECT user_id, currency, balance_after, ledger_seq, change,
+                LAG(balance_after) OVER (
+                    PARTITION BY user_id, currency ORDER BY ledger_seq
+                ) AS previous_balance
+            FROM entries
+        ),
+        ledger AS (
+            SELECT user_id, currency, SUM(change) AS ledger_balance,
+                (ARRAY_AGG(balance_after ORDER BY ledger_seq DESC))[1] AS last_balance_after,
+                COUNT(*) FILTER (
+                    WHERE balance_after <> COALESCE(previous_balance, 0) + change
+                ) AS broken_entries
+            FROM chained
+            GROUP BY user_id, currency
+        )
+        SELECT u.id as "user_id!", u.email, COALESCE(w.currency, l.currency) as "currency!",
+            COALESCE(w.balance, 0) as "wallet_balance!",
+            COALESCE(l.ledger_balance, 0) as "ledger_balance!",
+            l.last_balance_after, COALESCE(l.broken_entries, 0) as "broken_entries!"
+        FROM wallets w
+        FULL JOIN ledger l ON l.user_id = w.user_id AND l.currency = w.currency
+        JOIN users u ON u.id = COALESCE(w.user_id, l.user_id)
+        ORDER BY u.email, 3
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let wallets_checked = wallets.len();
+    let drifts = wallets
+        .into_iter()
+        .filter(|wallet| {
+            wallet.wallet_balance != wallet.ledger_balance
+                || wallet
+                    .last_balance_after
+                    .is_some_and(|balance| balance != wallet.wallet_balance)
+                || wallet.broken_entries > 0
+        })
+        .map(|wallet| WalletDrift {
+            user_id: wallet.user_id,
+            email: wallet.email,
+            currency: wallet.currency,
+            wallet_balance: wallet.wallet_balance,
+            ledger_balance: wallet.ledger_balance,
+            drift: wallet.wallet_balance - wallet.ledger_balance,
+            last_balance_after: wallet.last_balance_after,
+            broken_entries: wallet.broken_entries,
+        })
+        .collect();
+
+    Ok(ReconciliationReport {
+        wallets_checked,
+        drifts,
+    })
+}
+
+#[get("/admin/reconciliation")]
+pub async fn get_reconciliation(
+    _support: Authenticated<SupportRole>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    match reconcile(&pool).await {
+        Ok(report) => json_response(ApiResponse::success(report)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to reconcile the ledger".to_string(),
+        )),
+    }
+}
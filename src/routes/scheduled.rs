@@ -0,0 +1,353 @@
+use crate::engine::{
+    database_error, run_transfer, validate_details, SendTransactionRequest, TransferError,
+};
+use crate::utils::{
+    auth::Authenticated,
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::{env, time::Duration};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleTransferRequest {
+    amount: Decimal,
+    email: String,
+    currency: Option<String>,
+    memo: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    execute_at: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub struct ScheduledTransferResponse {
+    id: Uuid,
+    receiver_email: String,
+    amount: Decimal,
+    currency: String,
+    memo: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    execute_at: OffsetDateTime,
+    status: String,
+    transfer_id: Option<Uuid>,
+    failure_reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    executed_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+// Runs that fail on a database error are retried this many times before they are failed
+pub const MAX_RUN_ATTEMPTS: i32 = 5;
+
+// How often the schedulers look for due work, configurable through SCHEDULED_TRANSFERS_POLL_SECONDS
+pub fn poll_interval() -> Duration {
+    Duration::from_secs(
+        env::var("SCHEDULED_TRANSFERS_POLL_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(10),
+    )
+}
+
+#[post("/transaction/scheduled")]
+pub async fn create_scheduled_transfer(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    schedule_request: web::Json<ScheduleTransferRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key =
+        match idempotency::begin(&req, &pool, claims.sub, &*schedule_request).await {
+            Idempotency::Proceed(key) => key,
+            Idempotency::Done(response) => return response,
+        };
+
+    let response = schedule(&pool, claims.sub, &schedule_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn schedule(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    schedule_request: &ScheduleTransferRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if schedule_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(schedule_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    if let Err(msg) = validate_details(schedule_request.memo.as_deref(), None) {
+        return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()));
+    }
+
+    if schedule_request.execute_at <= OffsetDateTime::now_utc() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "execute_at must be in the future".to_string(),
+        ));
+    }
+
+    // The receiver is checked again when the transfer runs
+    let scheduled = sqlx::query_as!(
+        ScheduledTransferResponse,
+        r#"
+        INSERT INTO scheduled_transfers (id, user_id, receiver_email, amount, currency, memo, execute_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, receiver_email, amount, currency, memo, execute_at,
+            status::text as "status!", transfer_id, failure_reason, executed_at, created_at
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        schedule_request.email,
+        schedule_request.amount,
+        currency,
+        schedule_request.memo,
+        schedule_request.execute_at
+    )
+    .fetch_one(pool)
+    .await;
+
+    match scheduled {
+        Ok(scheduled) => json_response(ApiResponse::success(scheduled)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to schedule transfer".to_string(),
+        )),
+    }
+}
+
+#[get("/transaction/scheduled")]
+pub async fn get_scheduled_transfers(
+    claims: Authenticated,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let scheduled = sqlx::query_as!(
+        ScheduledTransferResponse,
+        r#"
+        SELECT id, receiver_email, amount, currency, memo, execute_at,
+            status::text as "status!", transfer_id, failure_reason, executed_at, created_at
+        FROM scheduled_transfers
+        WHERE user_id = $1
+        ORDER BY execute_at, id
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match scheduled {
+        Ok(scheduled) => json_response(ApiResponse::success(scheduled)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch scheduled transfers".to_string(),
+        )),
+    }
+}
+
+#[post("/transaction/scheduled/{id}/cancel")]
+pub async fn cancel_scheduled_transfer(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let scheduled_id = path.into_inner();
+
+    // Only pending transfers can be cancelled, the scheduler holds a row lock while running one
+    let cancelled = sqlx::query_as!(
+        ScheduledTransferResponse,
+        r#"
+        UPDATE scheduled_transfers SET status = 'CANCELLED'
+        WHERE id = $1 AND user_id = $2 AND status = 'PENDING'
+        RETURNING id, receiver_email, amount, currency, memo, execute_at,
+            status::text as "status!", transfer_id, failure_reason, executed_at, created_at
+        "#,
+        scheduled_id,
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match cancelled {
+        Ok(Some(cancelled)) => return json_response(ApiResponse::success(cancelled)),
+        Ok(None) => {}
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to cancel scheduled transfer".to_string(),
+            ))
+        }
+    }
+
+    // Tell a missing transfer apart from one that already ran
+    let existing = sqlx::query!(
+        "SELECT id FROM scheduled_transfers WHERE id = $1 AND user_id = $2",
+        scheduled_id,
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match existing {
+        Ok(Some(_)) => json_response(ApiResponse::<MessageData>::error(
+            409,
+            "Only pending transfers can be cancelled".to_string(),
+        )),
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Scheduled transfer not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        )),
+    }
+}
+
+// Runs every due transfer once, returns how many were processed
+pub async fn run_due_transfers(pool: &sqlx::PgPool) -> Result<usize, sqlx::Error> {
+    let mut processed = 0;
+
+    // Each transfer is claimed and settled in its own transaction, so
+    // several schedulers never pick up the same row
+    loop {
+        let mut tx = pool.begin().await?;
+
+        let due = sqlx::query!(
+            r#"
+            SELECT id, user_id, receiver_email, amount, currency, memo
+            FROM scheduled_transfers
+            WHERE status = 'PENDING' AND execute_at <= NOW()
+                AND (retry_at IS NULL OR retry_at <= NOW())
+            ORDER BY execute_at, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+            "#
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(due) = due else {
+            return Ok(processed);
+        };
+        let scheduled_id = due.id;
+
+        let send_request = SendTransactionRequest {
+            amount: due.amount,
+            email: due.receiver_email,
+            memo: due.memo,
+            metadata: None,
+            currency: Some(due.currency),
+            receive_currency: None,
+            quote_id: None,
+        };
+
+        let settled = async move {
+            let (transfer_id, failure_reason) =
+                match run_transfer(&mut tx, due.user_id, &send_request).await {
+                    Ok(transfer_id) => (Some(transfer_id), None),
+                    Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                        return Err(error)
+                    }
+                    Err(error) => (
+                        None,
+                        Some(error.failure_reason().unwrap_or("INVALID_REQUEST")),
+                    ),
+                };
+
+            sqlx::query!(
+                r#"
+                UPDATE scheduled_transfers
+                SET status = $2::text::scheduled_transfer_status, transfer_id = $3,
+                    failure_reason = $4, executed_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                due.id,
+                if transfer_id.is_some() {
+                    "SUCCESS"
+                } else {
+                    "FAILURE"
+                },
+                transfer_id,
+                failure_reason
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error("Failed to update scheduled transfer"))?;
+
+            tx.commit()
+                .await
+                .map_err(database_error("Failed to commit transaction"))
+        }
+        .await;
+
+        // The run was rolled back. It is retried later so one bad row can't hold up the
+        // rows behind it
+        if let Err(error) = settled {
+            eprintln!(
+                "Scheduled transfer {} failed: {}",
+                scheduled_id,
+                error.status().1
+            );
+            record_failed_attempt(pool, scheduled_id).await?;
+        }
+        processed += 1;
+    }
+}
+
+// Pushes a run that failed on a database error back, twice as far each time, and fails it
+// for good once it used up MAX_RUN_ATTEMPTS
+async fn record_failed_attempt(pool: &sqlx::PgPool, scheduled_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE scheduled_transfers
+        SET attempts = attempts + 1,
+            retry_at = NOW() + make_interval(secs => $2::float8 * power(2, attempts)),
+            status = CASE WHEN attempts + 1 >= $3
+                THEN 'FAILURE'::scheduled_transfer_status ELSE status END,
+            failure_reason = CASE WHEN attempts + 1 >= $3
+                THEN 'PROCESSING_ERROR' ELSE failure_reason END,
+            executed_at = CASE WHEN attempts + 1 >= $3
+                THEN CURRENT_TIMESTAMP ELSE executed_at END
+        WHERE id = $1 AND status = 'PENDING'
+        "#,
+        scheduled_id,
+        poll_interval().as_secs_f64(),
+        MAX_RUN_ATTEMPTS
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn process_scheduled_transfers() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = run_due_transfers(&pool).await {
+            eprintln!("Failed to run scheduled transfers: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
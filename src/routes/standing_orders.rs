@@ -0,0 +1,592 @@
+use crate::engine::{
+    database_error, run_transfer, validate_details, SendTransactionRequest, TransferError,
+};
+use crate::routes::scheduled::{poll_interval, MAX_RUN_ATTEMPTS};
+use crate::utils::{
+    auth::Authenticated,
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+const INTERVAL_UNITS: [&str; 3] = ["DAY", "WEEK", "MONTH"];
+
+#[derive(Serialize, Deserialize)]
+pub struct StandingOrderRequest {
+    amount: Decimal,
+    email: String,
+    currency: Option<String>,
+    memo: Option<String>,
+    // One of DAY, WEEK or MONTH
+    interval_unit: String,
+    // Repeat every interval_count units, defaults to 1
+    interval_count: Option<i32>,
+    #[serde(with = "time::serde::rfc3339")]
+    start_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end_at: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct StandingOrderResponse {
+    id: Uuid,
+    receiver_email: String,
+    amount: Decimal,
+    currency: String,
+    memo: Option<String>,
+    interval_unit: String,
+    interval_count: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    start_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    end_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    next_run_at: OffsetDateTime,
+    status: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub struct StandingOrderRunResponse {
+    occurrence: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    scheduled_for: OffsetDateTime,
+    status: String,
+    transfer_id: Option<Uuid>,
+    failure_reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    executed_at: OffsetDateTime,
+}
+
+#[post("/transaction/standing")]
+pub async fn create_standing_order(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    order_request: web::Json<StandingOrderRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, claims.sub, &*order_request).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = create(&pool, claims.sub, &order_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn create(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    order_request: &StandingOrderRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if order_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(order_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+
+    if let Err(msg) = validate_details(order_request.memo.as_deref(), None) {
+        return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()));
+    }
+
+    if !INTERVAL_UNITS.contains(&order_request.interval_unit.as_str()) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Invalid interval_unit".to_string(),
+        ));
+    }
+
+    let interval_count = order_request.interval_count.unwrap_or(1);
+    if !(1..=1000).contains(&interval_count) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "interval_count must be between 1 and 1000".to_string(),
+        ));
+    }
+
+    if order_request.start_at <= OffsetDateTime::now_utc() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "start_at must be in the future".to_string(),
+        ));
+    }
+    if matches!(order_request.end_at, Some(end_at) if end_at < order_request.start_at) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "end_at must not be before start_at".to_string(),
+        ));
+    }
+
+    // The first run is at start_at
+    let order = sqlx::query_as!(
+        StandingOrderResponse,
+        r#"
+        INSERT INTO standing_orders
+            (id, user_id, receiver_email, amount, currency, memo, interval_unit, interval_count,
+             start_at, end_at, next_run_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7::text::standing_order_interval, $8, $9, $10, $9)
+        RETURNING id, receiver_email, amount, currency, memo,
+            interval_unit::text as "interval_unit!", interval_count, start_at, end_at,
+            next_run_at, status::text as "status!", created_at
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        order_request.email,
+        order_request.amount,
+        currency,
+        order_request.memo,
+        order_request.interval_unit,
+        interval_count,
+        order_request.start_at,
+        order_request.end_at
+    )
+    .fetch_one(pool)
+    .await;
+
+    match order {
+        Ok(order) => json_response(ApiResponse::success(order)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to create standing order".to_string(),
+        )),
+    }
+}
+
+#[get("/transaction/standing")]
+pub async fn get_standing_orders(
+    claims: Authenticated,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let orders = sqlx::query_as!(
+        StandingOrderResponse,
+        r#"
+        SELECT id, receiver_email, amount, currency, memo,
+            interval_unit::text as "interval_unit!", interval_count, start_at, end_at,
+            next_run_at, status::text as "status!", created_at
+        FROM standing_orders
+        WHERE user_id = $1
+        ORDER BY created_at, id
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match orders {
+        Ok(orders) => json_response(ApiResponse::success(orders)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch standing orders".to_string(),
+        )),
+    }
+}
+
+#[get("/transaction/standing/{id}/runs")]
+pub async fn get_standing_order_runs(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+
+    let order = sqlx::query!(
+        "SELECT id FROM standing_orders WHERE id = $1 AND user_id = $2",
+        order_id,
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match order {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "Standing order not found".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    }
+
+    let runs = sqlx::query_as!(
+        StandingOrderRunResponse,
+        r#"
+        SELECT occurrence, scheduled_for, status::text as "status!", transfer_id,
+            failure_reason, executed_at
+        FROM standing_order_runs
+        WHERE standing_order_id = $1
+        ORDER BY occurrence DESC
+        "#,
+        order_id
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match runs {
+        Ok(runs) => json_response(ApiResponse::success(runs)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch standing order runs".to_string(),
+        )),
+    }
+}
+
+#[post("/transaction/standing/{id}/pause")]
+pub async fn pause_standing_order(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    set_status(&pool, claims.sub, path.into_inner(), &["ACTIVE"], "PAUSED").await
+}
+
+#[post("/transaction/standing/{id}/cancel")]
+pub async fn cancel_standing_order(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    set_status(
+        &pool,
+        claims.sub,
+        path.into_inner(),
+        &["ACTIVE", "PAUSED"],
+        "CANCELLED",
+    )
+    .await
+}
+
+#[post("/transaction/standing/{id}/resume")]
+pub async fn resume_standing_order(
+    claims: Authenticated,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+
+    // Runs missed while paused are skipped, the order continues with the next future occurrence
+    let resumed = sqlx::query_as!(
+        StandingOrderResponse,
+        r#"
+        WITH next AS (
+            SELECT occurrence,
+                standing_order_run_at(start_at, interval_unit, interval_count, occurrence) AS run_at
+            FROM standing_orders, standing_order_next_occurrence(
+                start_at, interval_unit, interval_count, next_occurrence, NOW()) AS occurrence
+            WHERE id = $1
+        )
+        UPDATE standing_orders
+        SET next_occurrence = next.occurrence,
+            next_run_at = next.run_at,
+            status = CASE WHEN end_at IS NOT NULL AND next.run_at > end_at
+                THEN 'COMPLETED'::standing_order_status
+                ELSE 'ACTIVE'::standing_order_status END,
+            attempts = 0, retry_at = NULL,
+            updated_at = CURRENT_TIMESTAMP
+        FROM next
+        WHERE id = $1 AND user_id = $2 AND status = 'PAUSED'
+        RETURNING id, receiver_email, amount, currency, memo,
+            interval_unit::text as "interval_unit!", interval_count, start_at, end_at,
+            next_run_at, status::text as "status!", created_at
+        "#,
+        order_id,
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match resumed {
+        Ok(Some(order)) => json_response(ApiResponse::success(order)),
+        Ok(None) => not_updated(&pool, claims.sub, order_id).await,
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update standing order".to_string(),
+        )),
+    }
+}
+
+async fn set_status(
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    order_id: Uuid,
+    from_statuses: &[&str],
+    status: &str,
+) -> HttpResponse {
+    // The scheduler holds a row lock while running an order, so this waits for the run to finish
+    let updated = sqlx::query_as!(
+        StandingOrderResponse,
+        r#"
+        UPDATE standing_orders
+        SET status = $4::text::standing_order_status, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND user_id = $2 AND status::text = ANY($3)
+        RETURNING id, receiver_email, amount, currency, memo,
+            interval_unit::text as "interval_unit!", interval_count, start_at, end_at,
+            next_run_at, status::text as "status!", created_at
+        "#,
+        order_id,
+        user_id,
+        from_statuses as &[&str],
+        status
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match updated {
+        Ok(Some(order)) => json_response(ApiResponse::success(order)),
+        Ok(None) => not_updated(pool, user_id, order_id).await,
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update standing order".to_string(),
+        )),
+    }
+}
+
+// Tell a missing order apart from one in the wrong status
+async fn not_updated(pool: &sqlx::PgPool, user_id: Uuid, order_id: Uuid) -> HttpResponse {
+    let existing = sqlx::query!(
+        r#"SELECT status::text as "status!" FROM standing_orders WHERE id = $1 AND user_id = $2"#,
+        order_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match existing {
+        Ok(Some(order)) => json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Standing order is {}", order.status),
+        )),
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Standing order not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        )),
+    }
+}
+
+// Runs each due order once, returns how many were processed. When several occurrences are
+// due only the latest runs, the ones before it are skipped like they are while paused
+pub async fn run_due_standing_orders(pool: &sqlx::PgPool) -> Result<usize, sqlx::Error> {
+    let mut processed = 0;
+
+    // Each occurrence is claimed and settled in its own transaction
+    loop {
+        let mut tx = pool.begin().await?;
+
+        let due = sqlx::query!(
+            r#"
+            SELECT id, user_id, receiver_email, amount, currency, memo, occurrence as "occurrence!",
+                standing_order_run_at(start_at, interval_unit, interval_count, occurrence)
+                    as "scheduled_for!"
+            FROM standing_orders, LATERAL (
+                -- The latest occurrence that is due and not past end_at
+                SELECT standing_order_next_occurrence(start_at, interval_unit, interval_count,
+                    next_occurrence, LEAST(NOW(), end_at)) - 1 AS occurrence
+            ) due
+            WHERE status = 'ACTIVE' AND next_run_at <= NOW()
+                AND (retry_at IS NULL OR retry_at <= NOW())
+            ORDER BY next_run_at, id
+            LIMIT 1
+            FOR UPDATE OF standing_orders SKIP LOCKED
+            "#
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(due) = due else {
+            return Ok(processed);
+        };
+        let (order_id, occurrence, scheduled_for) = (due.id, due.occurrence, due.scheduled_for);
+
+        let send_request = SendTransactionRequest {
+            amount: due.amount,
+            email: due.receiver_email,
+            memo: due.memo,
+            metadata: None,
+            currency: Some(due.currency),
+            receive_currency: None,
+            quote_id: None,
+        };
+
+        let settled = async move {
+            // A declined run is recorded and the order moves on to the next occurrence
+            let (transfer_id, failure_reason) =
+                match run_transfer(&mut tx, due.user_id, &send_request).await {
+                    Ok(transfer_id) => (Some(transfer_id), None),
+                    Err(error @ (TransferError::Contention | TransferError::Database(_))) => {
+                        return Err(error)
+                    }
+                    Err(error) => (
+                        None,
+                        Some(error.failure_reason().unwrap_or("INVALID_REQUEST")),
+                    ),
+                };
+
+            finish_occurrence(
+                &mut tx,
+                order_id,
+                occurrence,
+                scheduled_for,
+                transfer_id,
+                failure_reason,
+            )
+            .await
+            .map_err(database_error("Failed to update standing order"))?;
+
+            tx.commit()
+                .await
+                .map_err(database_error("Failed to commit transaction"))
+        }
+        .await;
+
+        // The run was rolled back. It is retried later so one bad order can't hold up the
+        // orders behind it
+        if let Err(error) = settled {
+            eprintln!("Standing order {} failed: {}", order_id, error.status().1);
+            record_failed_attempt(pool, order_id, occurrence, scheduled_for).await?;
+        }
+        processed += 1;
+    }
+}
+
+// Records how an occurrence ended and moves the order on to its next future occurrence,
+// completing it once that is past end_at
+async fn finish_occurrence(
+    conn: &mut sqlx::PgConnection,
+    order_id: Uuid,
+    occurrence: i32,
+    scheduled_for: OffsetDateTime,
+    transfer_id: Option<Uuid>,
+    failure_reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO standing_order_runs
+            (id, standing_order_id, occurrence, scheduled_for, status, transfer_id,
+             failure_reason)
+        VALUES ($1, $2, $3, $4, $5::text::transaction_status, $6, $7)
+        "#,
+        Uuid::new_v4(),
+        order_id,
+        occurrence,
+        scheduled_for,
+        if transfer_id.is_some() {
+            "SUCCESS"
+        } else {
+            "FAILURE"
+        },
+        transfer_id,
+        failure_reason
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        WITH next AS (
+            SELECT occurrence,
+                standing_order_run_at(start_at, interval_unit, interval_count, occurrence) AS run_at
+            FROM standing_orders, standing_order_next_occurrence(
+                start_at, interval_unit, interval_count, $2 + 1, NOW()) AS occurrence
+            WHERE id = $1
+        )
+        UPDATE standing_orders
+        SET next_occurrence = next.occurrence,
+            next_run_at = next.run_at,
+            status = CASE WHEN end_at IS NOT NULL AND next.run_at > end_at
+                THEN 'COMPLETED'::standing_order_status
+                ELSE status END,
+            attempts = 0, retry_at = NULL,
+            updated_at = CURRENT_TIMESTAMP
+        FROM next
+        WHERE id = $1
+        "#,
+        order_id,
+        occurrence
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// Pushes a run that failed on a database error back, twice as far each time. Once it used
+// up MAX_RUN_ATTEMPTS the occurrence is recorded as failed and the order moves on
+async fn record_failed_attempt(
+    pool: &sqlx::PgPool,
+    order_id: Uuid,
+    occurrence: i32,
+    scheduled_for: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let order = sqlx::query!(
+        r#"
+        UPDATE standing_orders
+        SET attempts = attempts + 1,
+            retry_at = NOW() + make_interval(secs => $2::float8 * power(2, attempts)),
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status = 'ACTIVE'
+        RETURNING attempts
+        "#,
+        order_id,
+        poll_interval().as_secs_f64()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if order.is_some_and(|order| order.attempts >= MAX_RUN_ATTEMPTS) {
+        finish_occurrence(
+            &mut tx,
+            order_id,
+            occurrence,
+            scheduled_for,
+            None,
+            Some("PROCESSING_ERROR"),
+        )
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn process_standing_orders() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = run_due_standing_orders(&pool).await {
+            eprintln!("Failed to run standing orders: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
@@ -0,0 +1,481 @@
+use crate::utils::{
+    auth::{Scoped, TransactionsRead},
+    currency::parse_currency,
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, http::header, web, web::Bytes, HttpResponse, Responder};
+use futures_util::stream;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+use uuid::Uuid;
+
+// Entries fetched per chunk of the streamed statement
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    // csv, ofx or camt053
+    format: String,
+    // The statement covers one wallet, defaults to USD
+    currency: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    // Exclusive, defaults to now
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+}
+
+#[derive(Clone, Copy)]
+enum StatementFormat {
+    Csv,
+    Ofx,
+    Camt053,
+}
+
+impl StatementFormat {
+    fn parse(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "csv" => Some(StatementFormat::Csv),
+            "ofx" => Some(StatementFormat::Ofx),
+            "camt053" => Some(StatementFormat::Camt053),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "text/csv; charset=utf-8",
+            StatementFormat::Ofx => "application/x-ofx",
+            StatementFormat::Camt053 => "application/xml",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "csv",
+            StatementFormat::Ofx => "ofx",
+            StatementFormat::Camt053 => "xml",
+        }
+    }
+}
+
+struct Statement {
+    format: StatementFormat,
+    user_id: Uuid,
+    currency: String,
+    from: Option<OffsetDateTime>,
+    to: OffsetDateTime,
+    opening_balance: Decimal,
+    closing_balance: Decimal,
+    // Entries after the opening balance's entry up to the closing balance's one
+    first_seq: i64,
+    last_seq: i64,
+    generated_at: OffsetDateTime,
+}
+
+struct StatementEntry {
+    id: Uuid,
+    transfer_id: Uuid,
+    transaction_type: String,
+    amount: Decimal,
+    memo: Option<String>,
+    counterparty_email: Option<String>,
+    balance_after: Decimal,
+    created_at: OffsetDateTime,
+    ledger_seq: i64,
+}
+
+impl StatementEntry {
+    fn is_debit(&self) -> bool {
+        matches!(
+            self.transaction_type.as_str(),
+            "SENT" | "REFUND_SENT" | "WITHDRAWAL" | "FEE" | "FEE_REFUND_SENT"
+        )
+    }
+
+    fn signed_amount(&self) -> Decimal {
+        if self.is_debit() {
+            -self.amount
+        } else {
+            self.amount
+        }
+    }
+}
+
+enum ExportState {
+    Header,
+    // Entries after this ledger_seq are still to be sent
+    Entries(i64),
+    Footer,
+    Done,
+}
+
+#[get("/transactions/export")]
+pub async fn export_transactions(
+    caller: Scoped<TransactionsRead>,
+    query: web::Query<ExportQuery>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let Some(format) = StatementFormat::parse(&query.format) else {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "format must be csv, ofx or camt053".to_string(),
+        ));
+    };
+    let currency = match parse_currency(query.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+    let generated_at = OffsetDateTime::now_utc();
+    let to = query.to.unwrap_or(generated_at);
+    if matches!(query.from, Some(from) if from >= to) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "from must be before to".to_string(),
+        ));
+    }
+
+    // The period is cut on ledger_seq: each end is the last entry created before it, the
+    // balances are the running balance at those entries and the entries are the ones
+    // between them, so they always add up
+    let balances = sqlx::query!(
+        r#"
+        WITH bounds AS (
+            SELECT
+                COALESCE((
+                    SELECT ledger_seq FROM transactions
+                    WHERE user_id = $1 AND currency = $2 AND status <> 'FAILURE'
+                        AND created_at < $3
+                    ORDER BY ledger_seq DESC
+                    LIMIT 1
+                ), 0) as first_seq,
+                COALESCE((
+                    SELECT ledger_seq FROM transactions
+                    WHERE user_id = $1 AND currency = $2 AND status <> 'FAILURE'
+                        AND created_at < $4
+                    ORDER BY ledger_seq DESC
+                    LIMIT 1
+                ), 0) as last_seq
+        )
+        SELECT
+            first_seq as "first_seq!",
+            last_seq as "last_seq!",
+            COALESCE((
+                SELECT balance_after FROM transactions
+                WHERE user_id = $1 AND currency = $2 AND status <> 'FAILURE'
+                    AND ledger_seq <= first_seq
+                ORDER BY ledger_seq DESC
+                LIMIT 1
+            ), 0) as "opening_balance!",
+            COALESCE((
+                SELECT balance_after FROM transactions
+                WHERE user_id = $1 AND currency = $2 AND status <> 'FAILURE'
+                    AND ledger_seq <= last_seq
+                ORDER BY ledger_seq DESC
+                LIMIT 1
+            ), 0) as "closing_balance!"
+        FROM bounds
+        "#,
+        caller.sub,
+        currency,
+        query.from,
+        to
+    )
+    .fetch_one(&**pool)
+    .await;
+
+    let balances = match balances {
+        Ok(balances) => balances,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to fetch balances".to_string(),
+            ))
+        }
+    };
+
+    let statement = Statement {
+        format,
+        user_id: caller.sub,
+        currency,
+        from: query.from,
+        to,
+        opening_balance: balances.opening_balance,
+        closing_balance: balances.closing_balance,
+        first_seq: balances.first_seq,
+        last_seq: balances.last_seq,
+        generated_at,
+    };
+    let filename = format!("statement-{}.{}", statement.currency, format.extension());
+    let pool = pool.get_ref().clone();
+
+    // Entries are fetched a page at a time while the response is being sent
+    let body = stream::unfold(
+        (ExportState::Header, pool, statement),
+        |(state, pool, statement)| async move {
+            match state {
+                ExportState::Header => {
+                    let chunk = statement.header();
+                    Some((
+                        Ok(Bytes::from(chunk)),
+                        (ExportState::Entries(statement.first_seq), pool, statement),
+                    ))
+                }
+                ExportState::Entries(after_seq) => {
+                    match fetch_entries(&pool, &statement, after_seq).await {
+                        Ok(entries) => {
+                            let next = match entries.last() {
+                                Some(last) if entries.len() as i64 == EXPORT_PAGE_SIZE => {
+                                    ExportState::Entries(last.ledger_seq)
+                                }
+                                _ => ExportState::Footer,
+                            };
+                            let chunk: String =
+                                entries.iter().map(|entry| statement.entry(entry)).collect();
+                            Some((Ok(Bytes::from(chunk)), (next, pool, statement)))
+                        }
+                        Err(e) => Some((
+                            Err(actix_web::error::ErrorInternalServerError(e)),
+                            (ExportState::Done, pool, statement),
+                        )),
+                    }
+                }
+                ExportState::Footer => {
+                    let chunk = statement.footer();
+                    Some((Ok(Bytes::from(chunk)), (ExportState::Done, pool, statement)))
+                }
+                ExportState::Done => None,
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .streaming(body)
+}
+
+// Settled entries of the statement's wallet and period, in ledger order
+async fn fetch_entries(
+    pool: &sqlx::PgPool,
+    statement: &Statement,
+    after_seq: i64,
+) -> Result<Vec<StatementEntry>, sqlx::Error> {
+    sqlx::query_as!(
+        StatementEntry,
+        r#"
+        SELECT
+            t.id,
+            t.transfer_id,
+            t.transaction_type::text as "transaction_type!",
+            t.amount,
+            t.memo,
+            c.email as "counterparty_email?",
+            t.balance_after,
+            t.created_at,
+            t.ledger_seq
+        FROM transactions t
+        LEFT JOIN users c ON c.id = t.counterparty_id
+        WHERE t.user_id = $1 AND t.currency = $2 AND t.status <> 'FAILURE'
+            AND t.ledger_seq > $3 AND t.ledger_seq <= $4
+        ORDER BY t.ledger_seq
+        LIMIT $5
+        "#,
+        statement.user_id,
+        statement.currency,
+        after_seq,
+        statement.last_seq,
+        EXPORT_PAGE_SIZE
+    )
+    .fetch_all(pool)
+    .await
+}
+
+impl Statement {
+    fn header(&self) -> String {
+        match self.format {
+            StatementFormat::Csv => format!(
+                "date,transaction_id,transfer_id,type,counterparty,memo,amount,currency,balance_after\n\
+                 {},,,OPENING_BALANCE,,,,{},{}\n",
+                self.from.map(rfc3339).unwrap_or_default(),
+                self.currency,
+                self.opening_balance
+            ),
+            StatementFormat::Ofx => format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n\
+                 <?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n\
+                 <OFX>\n\
+                 <SIGNONMSGSRSV1><SONRS>\
+                 <STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\
+                 <DTSERVER>{}</DTSERVER><LANGUAGE>ENG</LANGUAGE>\
+                 </SONRS></SIGNONMSGSRSV1>\n\
+                 <BANKMSGSRSV1><STMTTRNRS><TRNUID>{}</TRNUID>\
+                 <STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n\
+                 <STMTRS><CURDEF>{}</CURDEF>\n\
+                 <BANKACCTFROM><BANKID>PAYMENTSYSTEM</BANKID><ACCTID>{}</ACCTID>\
+                 <ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n\
+                 <BANKTRANLIST><DTSTART>{}</DTSTART><DTEND>{}</DTEND>\n",
+                ofx_datetime(self.generated_at),
+                Uuid::new_v4(),
+                self.currency,
+                self.user_id,
+                ofx_datetime(self.from.unwrap_or(OffsetDateTime::UNIX_EPOCH)),
+                ofx_datetime(self.to)
+            ),
+            StatementFormat::Camt053 => format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:camt.053.001.02\">\n\
+                 <BkToCstmrStmt>\n\
+                 <GrpHdr><MsgId>{}</MsgId><CreDtTm>{}</CreDtTm></GrpHdr>\n\
+                 <Stmt>\n\
+                 <Id>{}</Id><CreDtTm>{}</CreDtTm>\n\
+                 <FrToDt><FrDtTm>{}</FrDtTm><ToDtTm>{}</ToDtTm></FrToDt>\n\
+                 <Acct><Id><Othr><Id>{}</Id></Othr></Id><Ccy>{}</Ccy></Acct>\n\
+                 {}\n{}\n",
+                Uuid::new_v4().simple(),
+                rfc3339(self.generated_at),
+                Uuid::new_v4().simple(),
+                rfc3339(self.generated_at),
+                rfc3339(self.from.unwrap_or(OffsetDateTime::UNIX_EPOCH)),
+                rfc3339(self.to),
+                self.user_id,
+                self.currency,
+                self.camt_balance("OPBD", self.opening_balance, self.from),
+                self.camt_balance("CLBD", self.closing_balance, Some(self.to))
+            ),
+        }
+    }
+
+    fn entry(&self, entry: &StatementEntry) -> String {
+        let counterparty = entry.counterparty_email.as_deref().unwrap_or_default();
+        let memo = entry.memo.as_deref().unwrap_or_default();
+        match self.format {
+            StatementFormat::Csv => format!(
+                "{},{},{},{},{},{},{},{},{}\n",
+                rfc3339(entry.created_at),
+                entry.id,
+                entry.transfer_id,
+                entry.transaction_type,
+                csv_field(counterparty),
+                csv_field(memo),
+                entry.signed_amount(),
+                self.currency,
+                entry.balance_after
+            ),
+            StatementFormat::Ofx => format!(
+                "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT>\
+                 <FITID>{}</FITID><NAME>{}</NAME><MEMO>{}</MEMO></STMTTRN>\n",
+                if entry.is_debit() { "DEBIT" } else { "CREDIT" },
+                ofx_datetime(entry.created_at),
+                entry.signed_amount(),
+                entry.id,
+                // NAME is at most 32 characters in OFX
+                xml_escape(&counterparty.chars().take(32).collect::<String>()),
+                xml_escape(if memo.is_empty() {
+                    &entry.transaction_type
+                } else {
+                    memo
+                })
+            ),
+            StatementFormat::Camt053 => {
+                let party = if entry.is_debit() { "Cdtr" } else { "Dbtr" };
+                format!(
+                    "<Ntry><NtryRef>{}</NtryRef><Amt Ccy=\"{}\">{}</Amt><CdtDbtInd>{}</CdtDbtInd>\
+                     <Sts>BOOK</Sts><BookgDt><DtTm>{}</DtTm></BookgDt><ValDt><DtTm>{}</DtTm></ValDt>\
+                     <BkTxCd><Prtry><Cd>{}</Cd></Prtry></BkTxCd>\
+                     <NtryDtls><TxDtls><Refs><EndToEndId>{}</EndToEndId></Refs>\
+                     <RltdPties><{party}><Nm>{}</Nm></{party}></RltdPties>\
+                     <RmtInf><Ustrd>{}</Ustrd></RmtInf></TxDtls></NtryDtls></Ntry>\n",
+                    entry.id,
+                    self.currency,
+                    entry.amount,
+                    if entry.is_debit() { "DBIT" } else { "CRDT" },
+                    rfc3339(entry.created_at),
+                    rfc3339(entry.created_at),
+                    entry.transaction_type,
+                    entry.transfer_id,
+                    xml_escape(counterparty),
+                    xml_escape(memo),
+                    party = party
+                )
+            }
+        }
+    }
+
+    fn footer(&self) -> String {
+        match self.format {
+            StatementFormat::Csv => format!(
+                "{},,,CLOSING_BALANCE,,,,{},{}\n",
+                rfc3339(self.to),
+                self.currency,
+                self.closing_balance
+            ),
+            StatementFormat::Ofx => format!(
+                "</BANKTRANLIST>\n\
+                 <LEDGERBAL><BALAMT>{}</BALAMT><DTASOF>{}</DTASOF></LEDGERBAL>\n\
+                 </STMTRS></STMTTRNRS></BANKMSGSRSV1>\n\
+                 </OFX>\n",
+                self.closing_balance,
+                ofx_datetime(self.to)
+            ),
+            StatementFormat::Camt053 => "</Stmt>\n</BkToCstmrStmt>\n</Document>\n".to_string(),
+        }
+    }
+
+    // Wallets never go negative, so balances are always credits
+    fn camt_balance(&self, code: &str, amount: Decimal, at: Option<OffsetDateTime>) -> String {
+        format!(
+            "<Bal><Tp><CdOrPrtry><Cd>{}</Cd></CdOrPrtry></Tp><Amt Ccy=\"{}\">{}</Amt>\
+             <CdtDbtInd>CRDT</CdtDbtInd><Dt><DtTm>{}</DtTm></Dt></Bal>",
+            code,
+            self.currency,
+            amount,
+            rfc3339(at.unwrap_or(OffsetDateTime::UNIX_EPOCH))
+        )
+    }
+}
+
+fn rfc3339(datetime: OffsetDateTime) -> String {
+    datetime.format(&Rfc3339).unwrap_or_default()
+}
+
+// OFX dates are YYYYMMDDHHMMSS with the zone in brackets
+fn ofx_datetime(datetime: OffsetDateTime) -> String {
+    let datetime = datetime.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}[0:GMT]",
+        datetime.year(),
+        u8::from(datetime.month()),
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    // Spreadsheets would run values like =SUM(...) as formulas
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,441 @@
+use crate::connectors::{Charge, FundingProvider, SettlementOutcome, TopUpOrder};
+use crate::engine::lock_wallets;
+use crate::routes::scheduled::poll_interval;
+use crate::utils::{
+    auth::Authenticated,
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct TopUpRequest {
+    amount: Decimal,
+    currency: Option<String>,
+    // Card token or account the funding provider charges
+    source: String,
+}
+
+#[derive(Serialize)]
+pub struct TopUpResponse {
+    id: Uuid,
+    amount: Decimal,
+    currency: String,
+    source: String,
+    status: String,
+    provider_reference: Option<String>,
+    failure_reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+}
+
+async fn find_top_up(pool: &sqlx::PgPool, top_up_id: Uuid) -> Result<TopUpResponse, sqlx::Error> {
+    sqlx::query_as!(
+        TopUpResponse,
+        r#"
+        SELECT id, amount, currency, source, status::text as "status!",
+            provider_reference, failure_reason, created_at, updated_at
+        FROM top_ups
+        WHERE id = $1
+        "#,
+        top_up_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+fn top_up_response(result: Result<TopUpResponse, sqlx::Error>) -> HttpResponse {
+    match result {
+        Ok(top_up) => json_response(ApiResponse::success(top_up)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch top-up".to_string(),
+        )),
+    }
+}
+
+#[post("/balance/top-up")]
+pub async fn create_top_up(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    top_up_request: web::Json<TopUpRequest>,
+    pool: web::Data<sqlx::PgPool>,
+    provider: web::Data<dyn FundingProvider>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, claims.sub, &*top_up_request).await
+    {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = top_up(&pool, &**provider, claims.sub, &top_up_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+// Records the top-up as PENDING, then asks the provider to charge it. The wallet is
+// credited once the charge is captured, right away or through the callback
+async fn top_up(
+    pool: &sqlx::PgPool,
+    provider: &dyn FundingProvider,
+    user_id: Uuid,
+    top_up_request: &TopUpRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if top_up_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(top_up_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+    let source = top_up_request.source.trim();
+    if source.is_empty() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "source is required".to_string(),
+        ));
+    }
+
+    // Until the provider answers, the top-up is charged again once retry_at passes
+    let top_up_id = Uuid::new_v4();
+    let created = sqlx::query_as!(
+        TopUpResponse,
+        r#"
+        INSERT INTO top_ups (id, user_id, amount, currency, source, retry_at)
+        VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))
+        RETURNING id, amount, currency, source, status::text as "status!",
+            provider_reference, failure_reason, created_at, updated_at
+        "#,
+        top_up_id,
+        user_id,
+        top_up_request.amount,
+        currency,
+        source,
+        poll_interval().as_secs_f64()
+    )
+    .fetch_one(pool)
+    .await;
+
+    let created = match created {
+        Ok(created) => created,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to create top-up".to_string(),
+            ))
+        }
+    };
+
+    // The provider has been asked to charge, so from here on the top-up is reported as
+    // created. A server error would let a retry with the same Idempotency-Key charge again
+    let order = TopUpOrder {
+        id: top_up_id,
+        amount: top_up_request.amount,
+        currency,
+        source: source.to_string(),
+    };
+    if let Err(e) = charge_top_up(pool, provider, &order).await {
+        eprintln!("Failed to charge top-up {}: {}", top_up_id, e);
+    }
+
+    match find_top_up(pool, top_up_id).await {
+        Ok(top_up) => top_up_created(top_up),
+        // The top-up as it was created
+        Err(_) => top_up_created(created),
+    }
+}
+
+// 202 while the provider hasn't answered yet, the top-up is charged again later
+fn top_up_created(top_up: TopUpResponse) -> HttpResponse {
+    let status_code = if top_up.status == "PENDING" && top_up.provider_reference.is_none() {
+        202
+    } else {
+        200
+    };
+    json_response(ApiResponse {
+        status_code,
+        ..ApiResponse::success(top_up)
+    })
+}
+
+// Asks the provider to charge a top-up and records its answer. Until a captured charge is
+// credited, or a pending one recorded, the top-up stays due to be charged again
+async fn charge_top_up(
+    pool: &sqlx::PgPool,
+    provider: &dyn FundingProvider,
+    order: &TopUpOrder,
+) -> Result<(), String> {
+    match provider.charge(order).await? {
+        Charge::Captured(reference) => {
+            set_provider_reference(pool, order.id, &reference, false)
+                .await
+                .map_err(|e| e.to_string())?;
+            finish_top_up(pool, order.id, &SettlementOutcome::Completed)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        // The callback finishes it
+        Charge::Pending(reference) => set_provider_reference(pool, order.id, &reference, true)
+            .await
+            .map_err(|e| e.to_string()),
+        // Declined right away, nothing was credited
+        Charge::Declined(reason) => {
+            finish_top_up(pool, order.id, &SettlementOutcome::Failed(reason))
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// A pending charge waits for the callback instead of being charged again
+async fn set_provider_reference(
+    pool: &sqlx::PgPool,
+    top_up_id: Uuid,
+    reference: &str,
+    awaits_callback: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE top_ups
+        SET provider_reference = $2, retry_at = CASE WHEN $3 THEN NULL ELSE retry_at END,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+        top_up_id,
+        reference,
+        awaits_callback
+    )
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+// Charges top-ups the provider hasn't answered for, or whose captured charge wasn't
+// credited, again. Returns how many were due
+pub async fn recharge_top_ups(
+    pool: &sqlx::PgPool,
+    provider: &dyn FundingProvider,
+) -> Result<u64, sqlx::Error> {
+    // Claimed by pushing retry_at back, twice as far each time, so they aren't picked up
+    // again while the provider is being asked
+    let due = sqlx::query!(
+        r#"
+        UPDATE top_ups
+        SET attempts = attempts + 1,
+            retry_at = NOW() + make_interval(secs => $1::float8 * power(2, LEAST(attempts, 6)))
+        WHERE id IN (
+            SELECT id FROM top_ups
+            WHERE status = 'PENDING' AND retry_at <= NOW()
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, amount, currency, source
+        "#,
+        poll_interval().as_secs_f64()
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for top_up in &due {
+        let order = TopUpOrder {
+            id: top_up.id,
+            amount: top_up.amount,
+            currency: top_up.currency.clone(),
+            source: top_up.source.clone(),
+        };
+        if let Err(e) = charge_top_up(pool, provider, &order).await {
+            eprintln!("Failed to charge top-up {}: {}", top_up.id, e);
+        }
+    }
+    Ok(due.len() as u64)
+}
+
+pub async fn process_top_up_charges(provider: Arc<dyn FundingProvider>) {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = recharge_top_ups(&pool, &*provider).await {
+            eprintln!("Failed to charge top-ups: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
+
+#[get("/balance/top-ups")]
+pub async fn get_top_ups(claims: Authenticated, pool: web::Data<sqlx::PgPool>) -> impl Responder {
+    // Newest first
+    let top_ups = sqlx::query_as!(
+        TopUpResponse,
+        r#"
+        SELECT id, amount, currency, source, status::text as "status!",
+            provider_reference, failure_reason, created_at, updated_at
+        FROM top_ups
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match top_ups {
+        Ok(top_ups) => json_response(ApiResponse::success(top_ups)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch top-ups".to_string(),
+        )),
+    }
+}
+
+// Called by the funding provider, which authenticates the request itself
+#[post("/balance/top-up/callback")]
+pub async fn top_up_callback(
+    req: actix_web::HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<sqlx::PgPool>,
+    provider: web::Data<dyn FundingProvider>,
+) -> impl Responder {
+    let callback = match provider.parse_callback(&req, &body) {
+        Ok(callback) => callback,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
+    };
+    let final_status = match callback.outcome {
+        SettlementOutcome::Completed => "SUCCESS",
+        SettlementOutcome::Failed(_) => "FAILURE",
+    };
+
+    match finish_top_up(&pool, callback.top_up_id, &callback.outcome).await {
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Top-up not found".to_string(),
+        )),
+        // Providers retry callbacks, repeating the same outcome is fine
+        Ok(Some(status)) if status == "PENDING" || status == final_status => {
+            top_up_response(find_top_up(&pool, callback.top_up_id).await)
+        }
+        Ok(Some(status)) => json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Top-up is {}", status),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update top-up".to_string(),
+        )),
+    }
+}
+
+// Moves a PENDING top-up to its final status, a successful one is credited to the
+// wallet. Returns the status the top-up had before, None if it doesn't exist
+async fn finish_top_up(
+    pool: &sqlx::PgPool,
+    top_up_id: Uuid,
+    outcome: &SettlementOutcome,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Lock the top-up so it is credited once
+    let Some(top_up) = sqlx::query!(
+        r#"
+        SELECT user_id, amount, currency, status::text as "status!"
+        FROM top_ups
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        top_up_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    if top_up.status != "PENDING" {
+        return Ok(Some(top_up.status));
+    }
+
+    match outcome {
+        SettlementOutcome::Completed => {
+            sqlx::query!(
+                r#"
+                UPDATE top_ups
+                SET status = 'SUCCESS', retry_at = NULL, updated_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                top_up_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            lock_wallets(&mut tx, &[(top_up.user_id, top_up.currency.clone())]).await?;
+            let updated_wallet = sqlx::query!(
+                r#"
+                UPDATE wallets SET balance = balance + $1, updated_at = CURRENT_TIMESTAMP
+                WHERE user_id = $2 AND currency = $3
+                RETURNING balance
+                "#,
+                top_up.amount,
+                top_up.user_id,
+                top_up.currency
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            // The ledger entry shares its transfer_id with the top-up
+            sqlx::query!(
+                r#"
+                INSERT INTO transactions
+                    (id, transfer_id, user_id, transaction_type, amount, currency, status,
+                     balance_after)
+                VALUES ($1, $2, $3, 'TOP_UP', $4, $5, 'SUCCESS', $6)
+                "#,
+                Uuid::new_v4(),
+                top_up_id,
+                top_up.user_id,
+                top_up.amount,
+                top_up.currency,
+                updated_wallet.balance
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        SettlementOutcome::Failed(reason) => {
+            sqlx::query!(
+                r#"
+                UPDATE top_ups
+                SET status = 'FAILURE', failure_reason = $2, retry_at = NULL,
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                top_up_id,
+                reason
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(Some(top_up.status))
+}
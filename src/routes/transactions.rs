@@ -1,225 +1,584 @@
-use crate::utils::{
-    auth,
-    response::{json_response, ApiResponse, MessageData},
-};
-use actix_web::{get, post, web, Responder};
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-
-#[derive(Serialize)]
-pub struct TransactionResponse {
-    id: Uuid,
-    transaction_type: String,
-    amount: Decimal,
-    status: String,
-}
-
-#[derive(Deserialize)]
-pub struct SendTransactionRequest {
-    amount: Decimal,
-    email: String,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct SendTransactionResponse {
-    amount: Decimal,
-    receiver_email: String,
-    balance: Decimal,
-    message: String,
-}
-
-#[get("/transactions")]
-pub async fn get_transactions(
-    req: actix_web::HttpRequest,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Verify token and get claims
-    let claims = match auth::verify_request_token(&req) {
-        Ok(claims) => claims,
-        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
-    };
-
-    // Fetch transactions for the user
-    let transactions = sqlx::query_as!(
-        TransactionResponse,
-        r#"
-        SELECT 
-            id,
-            transaction_type::text as "transaction_type!",
-            amount,
-            status::text as "status!"
-        FROM transactions 
-        WHERE user_id = $1 
-        ORDER BY created_at DESC
-        "#,
-        claims.sub
-    )
-    .fetch_all(&**pool)
-    .await;
-
-    match transactions {
-        Ok(transactions) => json_response(ApiResponse::success(transactions)),
-        Err(_) => json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to fetch transactions".to_string(),
-        )),
-    }
-}
-
-#[post("/transaction/send")]
-pub async fn send_transaction(
-    req: actix_web::HttpRequest,
-    send_request: web::Json<SendTransactionRequest>,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Verify token and get claims
-    let claims = match auth::verify_request_token(&req) {
-        Ok(claims) => claims,
-        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
-    };
-
-    // Validate amount is positive
-    if send_request.amount <= Decimal::new(0, 0) {
-        return json_response(ApiResponse::<MessageData>::error(
-            400,
-            "Amount must be positive".to_string(),
-        ));
-    }
-
-    // Start a transaction
-    let mut tx = match pool.begin().await {
-        Ok(tx) => tx,
-        Err(_) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                500,
-                "Failed to start transaction".to_string(),
-            ))
-        }
-    };
-
-    // Get sender's current balance
-    let sender = match sqlx::query!(
-        "SELECT balance FROM users WHERE id = $1 FOR UPDATE",
-        claims.sub
-    )
-    .fetch_optional(&mut *tx)
-    .await
-    {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                404,
-                "Sender not found".to_string(),
-            ))
-        }
-        Err(_) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                500,
-                "Database error".to_string(),
-            ))
-        }
-    };
-
-    // Check if sender has sufficient balance
-    if sender.balance < send_request.amount {
-        return json_response(ApiResponse::<MessageData>::error(
-            400,
-            "Insufficient balance".to_string(),
-        ));
-    }
-
-    // Get receiver's ID and verify they exist
-    let receiver = match sqlx::query!(
-        "SELECT id FROM users WHERE email = $1 FOR UPDATE",
-        send_request.email
-    )
-    .fetch_optional(&mut *tx)
-    .await
-    {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                404,
-                "Receiver not found".to_string(),
-            ))
-        }
-        Err(_) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                500,
-                "Database error".to_string(),
-            ))
-        }
-    };
-
-    // Update sender's balance
-    let updated_sender = match sqlx::query!(
-        "UPDATE users SET balance = balance - $1 WHERE id = $2 RETURNING balance",
-        send_request.amount,
-        claims.sub
-    )
-    .fetch_one(&mut *tx)
-    .await
-    {
-        Ok(user) => user,
-        Err(_) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                500,
-                "Failed to update sender balance".to_string(),
-            ))
-        }
-    };
-
-    // Update receiver's balance
-    if let Err(_) = sqlx::query!(
-        "UPDATE users SET balance = balance + $1 WHERE id = $2",
-        send_request.amount,
-        receiver.id
-    )
-    .execute(&mut *tx)
-    .await
-    {
-        return json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to update receiver balance".to_string(),
-        ));
-    }
-
-    // Create transaction records for both sender and receiver
-    let sender_transaction_id = Uuid::new_v4();
-    let receiver_transaction_id = Uuid::new_v4();
-    if let Err(_) = sqlx::query!(
-        r#"
-        INSERT INTO transactions (id, user_id, transaction_type, amount, status)
-        VALUES ($1, $2, 'SENT', $3, 'SUCCESS'), ($4, $5, 'RECEIVED', $3, 'SUCCESS')
-        "#,
-        sender_transaction_id,
-        claims.sub,
-        send_request.amount,
-        receiver_transaction_id,
-        receiver.id
-    )
-    .execute(&mut *tx)
-    .await
-    {
-        return json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to create transaction records".to_string(),
-        ));
-    }
-
-    // Commit the transaction
-    if let Err(_) = tx.commit().await {
-        return json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to commit transaction".to_string(),
-        ));
-    }
-
-    json_response(ApiResponse::success(SendTransactionResponse {
-        amount: send_request.amount,
-        receiver_email: send_request.email.clone(),
-        balance: updated_sender.balance,
-        message: "Transaction successful".to_string(),
-    }))
-}
+use crate::engine::{
+    self, database_error, lock_wallets, retry::retry, SendTransactionRequest, TransferError,
+};
+use crate::utils::{
+    auth::{PaymentsWrite, Scoped, TransactionsRead},
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct TransactionResponse {
+    id: Uuid,
+    transfer_id: Uuid,
+    transaction_type: String,
+    amount: Decimal,
+    currency: String,
+    status: String,
+    failure_reason: Option<String>,
+    counterparty_email: Option<String>,
+    memo: Option<String>,
+    metadata: Option<serde_json::Value>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub struct TransactionDetailResponse {
+    id: Uuid,
+    transfer_id: Uuid,
+    transaction_type: String,
+    amount: Decimal,
+    currency: String,
+    status: String,
+    failure_reason: Option<String>,
+    counterparty_id: Option<Uuid>,
+    counterparty_email: Option<String>,
+    balance_after: Decimal,
+    memo: Option<String>,
+    metadata: Option<serde_json::Value>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct TransactionsQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    transaction_type: Option<String>,
+    status: Option<String>,
+    currency: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+    min_amount: Option<Decimal>,
+    max_amount: Option<Decimal>,
+    // Entries whose metadata has this key, and this value when metadata_value is set
+    metadata_key: Option<String>,
+    metadata_value: Option<String>,
+}
+
+const TRANSACTION_TYPES: &[&str] = &[
+    "SENT",
+    "RECEIVED",
+    "REFUND_SENT",
+    "REFUND_RECEIVED",
+    "WITHDRAWAL",
+    "WITHDRAWAL_REFUND",
+    "TOP_UP",
+    "FEE",
+    "FEE_INCOME",
+    "FEE_REFUND",
+    "FEE_REFUND_SENT",
+];
+const TRANSACTION_STATUSES: &[&str] = &["SUCCESS", "FAILURE", "REVERSED"];
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+#[derive(Serialize, Deserialize)]
+pub struct SendTransactionResponse {
+    transfer_id: Uuid,
+    amount: Decimal,
+    currency: String,
+    received_amount: Decimal,
+    received_currency: String,
+    receiver_email: String,
+    // Charged on top of amount
+    fee: Decimal,
+    balance: Decimal,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefundRequest {
+    // Refunds whatever is left of the transfer when omitted
+    amount: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct RefundResponse {
+    refund_id: Uuid,
+    original_transfer_id: Uuid,
+    amount: Decimal,
+    currency: String,
+    refunded_total: Decimal,
+    original_status: String,
+    balance: Decimal,
+}
+
+#[get("/transactions")]
+pub async fn get_transactions(
+    caller: Scoped<TransactionsRead>,
+    query: web::Query<TransactionsQuery>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Validate filters
+    let transaction_type = query.transaction_type.as_deref().map(str::to_uppercase);
+    if let Some(transaction_type) = &transaction_type {
+        if !TRANSACTION_TYPES.contains(&transaction_type.as_str()) {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Invalid transaction_type".to_string(),
+            ));
+        }
+    }
+    let status = query.status.as_deref().map(str::to_uppercase);
+    if let Some(status) = &status {
+        if !TRANSACTION_STATUSES.contains(&status.as_str()) {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Invalid status".to_string(),
+            ));
+        }
+    }
+    let (cursor_created_at, cursor_id) = match query.cursor.as_deref().map(decode_cursor) {
+        None => (None, None),
+        Some(Some((created_at, id))) => (Some(created_at), Some(id)),
+        Some(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Invalid cursor".to_string(),
+            ))
+        }
+    };
+    let currency = match query.currency.as_deref().map(|c| parse_currency(Some(c))) {
+        None => None,
+        Some(Ok(currency)) => Some(currency),
+        Some(Err(msg)) => {
+            return json_response(ApiResponse::<MessageData>::error(400, msg.to_string()))
+        }
+    };
+    if query.metadata_value.is_some() && query.metadata_key.is_none() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "metadata_value requires metadata_key".to_string(),
+        ));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // Fetch one page of transactions for the user, newest first
+    let transactions = sqlx::query_as!(
+        TransactionResponse,
+        r#"
+        SELECT 
+            t.id,
+            t.transfer_id,
+            t.transaction_type::text as "transaction_type!",
+            t.amount,
+            t.currency,
+            t.status::text as "status!",
+            t.failure_reason,
+            c.email as "counterparty_email?",
+            t.memo,
+            t.metadata,
+            t.created_at
+        FROM transactions t
+        LEFT JOIN users c ON c.id = t.counterparty_id
+        WHERE t.user_id = $1 
+            AND ($2::timestamptz IS NULL OR (t.created_at, t.id) < ($2, $3::uuid))
+            AND ($4::text IS NULL OR t.transaction_type::text = $4)
+            AND ($5::text IS NULL OR t.status::text = $5)
+            AND ($6::timestamptz IS NULL OR t.created_at >= $6)
+            AND ($7::timestamptz IS NULL OR t.created_at < $7)
+            AND ($8::decimal IS NULL OR t.amount >= $8)
+            AND ($9::decimal IS NULL OR t.amount <= $9)
+            AND ($10::text IS NULL OR t.currency = $10)
+            AND ($12::text IS NULL OR t.metadata ? $12)
+            AND ($13::text IS NULL OR t.metadata ->> $12 = $13)
+        ORDER BY t.created_at DESC, t.id DESC
+        LIMIT $11
+        "#,
+        caller.sub,
+        cursor_created_at,
+        cursor_id,
+        transaction_type,
+        status,
+        query.from,
+        query.to,
+        query.min_amount,
+        query.max_amount,
+        currency,
+        limit + 1,
+        query.metadata_key,
+        query.metadata_value
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match transactions {
+        Ok(mut transactions) => {
+            // The extra row only tells us whether another page exists
+            let next_cursor = if transactions.len() as i64 > limit {
+                transactions.truncate(limit as usize);
+                transactions
+                    .last()
+                    .map(|last| encode_cursor(last.created_at, last.id))
+            } else {
+                None
+            };
+            json_response(ApiResponse::page(transactions, next_cursor))
+        }
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch transactions".to_string(),
+        )),
+    }
+}
+
+#[get("/transactions/{id}")]
+pub async fn get_transaction(
+    caller: Scoped<TransactionsRead>,
+    path: web::Path<Uuid>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Only entries on the caller's own ledger are visible
+    let transaction = sqlx::query_as!(
+        TransactionDetailResponse,
+        r#"
+        SELECT
+            t.id,
+            t.transfer_id,
+            t.transaction_type::text as "transaction_type!",
+            t.amount,
+            t.currency,
+            t.status::text as "status!",
+            t.failure_reason,
+            t.counterparty_id,
+            c.email as "counterparty_email?",
+            t.balance_after,
+            t.memo,
+            t.metadata,
+            t.created_at,
+            t.updated_at
+        FROM transactions t
+        LEFT JOIN users c ON c.id = t.counterparty_id
+        WHERE t.id = $1 AND t.user_id = $2
+        "#,
+        path.into_inner(),
+        caller.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    match transaction {
+        Ok(Some(transaction)) => json_response(ApiResponse::success(transaction)),
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Transaction not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch transaction".to_string(),
+        )),
+    }
+}
+
+// Cursors point at the last row of a page as "<created_at unix nanos>_<id>"
+fn encode_cursor(created_at: OffsetDateTime, id: Uuid) -> String {
+    format!("{}_{}", created_at.unix_timestamp_nanos(), id)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(OffsetDateTime, Uuid)> {
+    let (nanos, id) = cursor.split_once('_')?;
+    let created_at = OffsetDateTime::from_unix_timestamp_nanos(nanos.parse().ok()?).ok()?;
+    Some((created_at, Uuid::parse_str(id).ok()?))
+}
+
+#[post("/transaction/send")]
+pub async fn send_transaction(
+    caller: Scoped<PaymentsWrite>,
+    req: actix_web::HttpRequest,
+    send_request: web::Json<SendTransactionRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, caller.sub, &*send_request).await {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = transfer(&pool, caller.sub, &send_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+async fn transfer(
+    pool: &sqlx::PgPool,
+    sender_id: Uuid,
+    send_request: &SendTransactionRequest,
+) -> HttpResponse {
+    let receipt = match engine::transfer(pool, sender_id, send_request).await {
+        Ok(receipt) => receipt,
+        Err(error) => return error.to_response(),
+    };
+
+    json_response(ApiResponse::success(SendTransactionResponse {
+        transfer_id: receipt.transfer_id,
+        amount: send_request.amount,
+        currency: receipt.currency,
+        received_amount: receipt.received_amount,
+        received_currency: receipt.received_currency,
+        receiver_email: send_request.email.clone(),
+        fee: receipt.fee,
+        balance: receipt.balance,
+        message: "Transaction successful".to_string(),
+    }))
+}
+
+#[post("/transactions/{id}/refund")]
+pub async fn refund_transaction(
+    caller: Scoped<PaymentsWrite>,
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    refund_request: web::Json<RefundRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key = match idempotency::begin(&req, &pool, caller.sub, &*refund_request).await
+    {
+        Idempotency::Proceed(key) => key,
+        Idempotency::Done(response) => return response,
+    };
+
+    let response = refund(&pool, caller.sub, path.into_inner(), &refund_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+// Pays back (part of) a transfer the caller received
+async fn refund(
+    pool: &sqlx::PgPool,
+    refunder_id: Uuid,
+    transaction_id: Uuid,
+    refund_request: &RefundRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if matches!(refund_request.amount, Some(amount) if amount <= Decimal::new(0, 0)) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    match retry(async || try_refund(pool, refunder_id, transaction_id, refund_request).await).await
+    {
+        Ok(response) => response,
+        Err(error) => error.to_response(),
+    }
+}
+
+// One attempt at a refund, declines come back as their response and database errors as
+// the TransferError that tells whether running it again can help
+async fn try_refund(
+    pool: &sqlx::PgPool,
+    refunder_id: Uuid,
+    transaction_id: Uuid,
+    refund_request: &RefundRequest,
+) -> Result<HttpResponse, TransferError> {
+    // Start a transaction
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(database_error("Failed to start transaction"))?;
+
+    // Lock the original entry so concurrent refunds of one transfer run one at a time
+    let original = sqlx::query!(
+        r#"
+        SELECT
+            transfer_id,
+            counterparty_id,
+            amount,
+            currency,
+            transaction_type::text as "transaction_type!",
+            status::text as "status!"
+        FROM transactions
+        WHERE id = $1 AND user_id = $2
+        FOR UPDATE
+        "#,
+        transaction_id,
+        refunder_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(database_error("Database error"))?;
+    let Some(original) = original else {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Transaction not found".to_string(),
+        )));
+    };
+
+    // Only money received from another user can be sent back
+    let payer_id = match original.counterparty_id {
+        Some(payer_id) if original.transaction_type == "RECEIVED" => payer_id,
+        _ => {
+            return Ok(json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Only received transfers can be refunded".to_string(),
+            )))
+        }
+    };
+    if original.status != "SUCCESS" {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Transaction can no longer be refunded".to_string(),
+        )));
+    }
+
+    // Refunds can never exceed the original amount
+    let refunded = sqlx::query!(
+        r#"
+        SELECT COALESCE(SUM(amount), 0) as "total!"
+        FROM transactions
+        WHERE refund_of = $1 AND transaction_type = 'REFUND_SENT'
+        "#,
+        original.transfer_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(database_error("Database error"))?
+    .total;
+    let refundable = original.amount - refunded;
+    let amount = refund_request.amount.unwrap_or(refundable);
+    if amount > refundable {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Refund exceeds the refundable amount".to_string(),
+        )));
+    }
+
+    lock_wallets(
+        &mut tx,
+        &[
+            (refunder_id, original.currency.clone()),
+            (payer_id, original.currency.clone()),
+        ],
+    )
+    .await
+    .map_err(database_error("Database error"))?;
+
+    // Get refunder's available balance in the transfer's currency
+    let refunder_balance = sqlx::query!(
+        r#"
+        SELECT balance - held as "available!" FROM wallets
+        WHERE user_id = $1 AND currency = $2
+        "#,
+        refunder_id,
+        original.currency
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(database_error("Database error"))?
+    .available;
+
+    // Check if refunder has sufficient balance
+    if refunder_balance < amount {
+        return Ok(json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Insufficient balance".to_string(),
+        )));
+    }
+
+    // Update refunder's balance
+    let updated_refunder = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance - $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3
+        RETURNING balance
+        "#,
+        amount,
+        refunder_id,
+        original.currency
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(database_error("Failed to update refunder balance"))?;
+
+    // Update original payer's balance
+    let updated_payer = sqlx::query!(
+        r#"
+        INSERT INTO wallets (user_id, currency, balance)
+        VALUES ($2, $3, $1)
+        ON CONFLICT (user_id, currency)
+        DO UPDATE SET balance = wallets.balance + EXCLUDED.balance,
+            updated_at = CURRENT_TIMESTAMP
+        RETURNING balance
+        "#,
+        amount,
+        payer_id,
+        original.currency
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(database_error("Failed to update payer balance"))?;
+
+    // Create compensating records linked to the original transfer
+    let refund_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions
+            (id, transfer_id, user_id, counterparty_id, transaction_type, amount, currency,
+             status, balance_after, refund_of)
+        VALUES
+            ($1, $2, $3, $5, 'REFUND_SENT', $4, $10, 'SUCCESS', $7, $9),
+            ($6, $2, $5, $3, 'REFUND_RECEIVED', $4, $10, 'SUCCESS', $8, $9)
+        "#,
+        Uuid::new_v4(),
+        refund_id,
+        refunder_id,
+        amount,
+        payer_id,
+        Uuid::new_v4(),
+        updated_refunder.balance,
+        updated_payer.balance,
+        original.transfer_id,
+        original.currency
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(database_error("Failed to create refund records"))?;
+
+    // A fully refunded transfer is reversed on both legs
+    let original_status = if amount == refundable {
+        sqlx::query!(
+            r#"
+            UPDATE transactions
+            SET status = 'REVERSED', updated_at = CURRENT_TIMESTAMP
+            WHERE transfer_id = $1 AND transaction_type IN ('SENT', 'RECEIVED')
+            "#,
+            original.transfer_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error("Failed to reverse transaction"))?;
+        "REVERSED".to_string()
+    } else {
+        original.status
+    };
+
+    // Commit the transaction
+    tx.commit()
+        .await
+        .map_err(database_error("Failed to commit transaction"))?;
+
+    Ok(json_response(ApiResponse::success(RefundResponse {
+        refund_id,
+        original_transfer_id: original.transfer_id,
+        amount,
+        currency: original.currency,
+        refunded_total: refunded + amount,
+        original_status,
+        balance: updated_refunder.balance,
+    })))
+}
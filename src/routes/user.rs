@@ -1,151 +1,373 @@
-use crate::utils::{
-    auth::{self, AuthData},
-    response::{json_response, ApiResponse, MessageData},
-};
-use actix_web::{get, post, web, Responder};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use email_address::EmailAddress;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-
-#[derive(Deserialize)]
-pub struct UserCredentials {
-    pub email: String,
-    pub password: String,
-}
-
-#[derive(Serialize)]
-pub struct UserData {
-    pub email: String,
-    pub balance: Decimal,
-}
-
-#[post("/user/register")]
-pub async fn register(
-    credentials: web::Json<UserCredentials>,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Validate email
-    if !EmailAddress::is_valid(&credentials.email) {
-        return json_response(ApiResponse::<MessageData>::error(
-            400,
-            "Invalid email".to_string(),
-        ));
-    }
-    // Check if user exists
-    let existing_user = sqlx::query!("SELECT id FROM users WHERE email = $1", credentials.email)
-        .fetch_optional(&**pool)
-        .await;
-
-    if let Ok(Some(_)) = existing_user {
-        return json_response(ApiResponse::<MessageData>::error(
-            409,
-            "User already exists".to_string(),
-        ));
-    }
-
-    // Hash password
-    let password_hash = match hash(credentials.password.as_bytes(), DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => {
-            return json_response(ApiResponse::<MessageData>::error(
-                500,
-                "Internal server error".to_string(),
-            ));
-        }
-    };
-
-    let balance = Decimal::new(0, 0);
-
-    // Create new user
-    let user_id = Uuid::new_v4();
-    let result = sqlx::query!(
-        "INSERT INTO users (id, email, password_hash, balance) VALUES ($1, $2, $3, $4)",
-        user_id,
-        credentials.email,
-        password_hash,
-        balance
-    )
-    .execute(&**pool)
-    .await;
-
-    match result {
-        Ok(_) => json_response(ApiResponse::success(MessageData {
-            message: "User created successfully".to_string(),
-        })),
-        Err(_) => json_response(ApiResponse::<MessageData>::error(
-            500,
-            "Failed to create user".to_string(),
-        )),
-    }
-}
-
-#[post("/user/login")]
-pub async fn login(
-    credentials: web::Json<UserCredentials>,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Fetch user
-    let user = sqlx::query!(
-        "SELECT id, password_hash FROM users WHERE email = $1",
-        credentials.email
-    )
-    .fetch_optional(&**pool)
-    .await;
-
-    match user {
-        Ok(Some(user)) => {
-            // Verify password
-            if verify(&credentials.password, &user.password_hash).unwrap_or(false) {
-                // Generate JWT
-                match auth::create_token(user.id) {
-                    Ok(token) => json_response(ApiResponse::success(AuthData { token })),
-                    Err(_) => json_response(ApiResponse::<MessageData>::error(
-                        500,
-                        "Failed to create token".to_string(),
-                    )),
-                }
-            } else {
-                json_response(ApiResponse::<MessageData>::error(
-                    401,
-                    "Invalid credentials".to_string(),
-                ))
-            }
-        }
-        _ => json_response(ApiResponse::<MessageData>::error(
-            401,
-            "Invalid credentials".to_string(),
-        )),
-    }
-}
-
-#[get("/user")]
-pub async fn get_user(
-    req: actix_web::HttpRequest,
-    pool: web::Data<sqlx::PgPool>,
-) -> impl Responder {
-    // Verify token and get claims
-    let claims = match auth::verify_request_token(&req) {
-        Ok(claims) => claims,
-        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
-    };
-
-    let user = sqlx::query!(
-        "SELECT id, email, balance FROM users WHERE id = $1",
-        claims.sub
-    )
-    .fetch_optional(&**pool)
-    .await;
-
-    match user {
-        Ok(Some(user)) => json_response(ApiResponse::success(UserData {
-            email: user.email,
-            balance: user.balance,
-        })),
-        _ => json_response(ApiResponse::<MessageData>::error(
-            404,
-            "User not found".to_string(),
-        )),
-    }
-}
+use crate::routes::balance::BalanceResponse;
+use crate::utils::{
+    auth::{self, AdminRole, AuthData, Authenticated, Role},
+    keys::JwtKeys,
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, Responder};
+use bcrypt::{hash, verify, DEFAULT_COST};
+use email_address::EmailAddress;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct UserCredentials {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    // Ends every session of the user, not just the one the token belongs to
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    pub email: String,
+    pub role: String,
+}
+
+#[derive(Serialize)]
+pub struct SetRoleResponse {
+    pub email: String,
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct UserData {
+    pub email: String,
+    pub role: String,
+    pub balances: Vec<BalanceResponse>,
+}
+
+#[post("/user/register")]
+pub async fn register(
+    credentials: web::Json<UserCredentials>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Validate email
+    if !EmailAddress::is_valid(&credentials.email) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Invalid email".to_string(),
+        ));
+    }
+    // Check if user exists
+    let existing_user = sqlx::query!("SELECT id FROM users WHERE email = $1", credentials.email)
+        .fetch_optional(&**pool)
+        .await;
+
+    if let Ok(Some(_)) = existing_user {
+        return json_response(ApiResponse::<MessageData>::error(
+            409,
+            "User already exists".to_string(),
+        ));
+    }
+
+    // Hash password
+    let password_hash = match hash(credentials.password.as_bytes(), DEFAULT_COST) {
+        Ok(hash) => hash,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Internal server error".to_string(),
+            ));
+        }
+    };
+
+    // Create new user, wallets are opened on first credit
+    let user_id = Uuid::new_v4();
+    let result = sqlx::query!(
+        "INSERT INTO users (id, email, password_hash) VALUES ($1, $2, $3)",
+        user_id,
+        credentials.email,
+        password_hash
+    )
+    .execute(&**pool)
+    .await;
+
+    match result {
+        Ok(_) => json_response(ApiResponse::success(MessageData {
+            message: "User created successfully".to_string(),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to create user".to_string(),
+        )),
+    }
+}
+
+#[post("/user/login")]
+pub async fn login(
+    credentials: web::Json<UserCredentials>,
+    pool: web::Data<sqlx::PgPool>,
+    keys: web::Data<JwtKeys>,
+) -> impl Responder {
+    // Fetch user
+    let user = sqlx::query!(
+        "SELECT id, password_hash FROM users WHERE email = $1",
+        credentials.email
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    // Verify password
+    let user = match user {
+        Ok(Some(user)) if verify(&credentials.password, &user.password_hash).unwrap_or(false) => {
+            user
+        }
+        _ => {
+            return json_response(ApiResponse::<MessageData>::error(
+                401,
+                "Invalid credentials".to_string(),
+            ))
+        }
+    };
+
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    // Every login starts a refresh token family of its own
+    match auth::issue_tokens(&mut conn, &keys, user.id, Uuid::new_v4()).await {
+        Ok(auth_data) => json_response(ApiResponse::success(auth_data)),
+        Err(msg) => json_response(ApiResponse::<MessageData>::error(500, msg.to_string())),
+    }
+}
+
+#[post("/user/token/refresh")]
+pub async fn refresh_token(
+    refresh_request: web::Json<RefreshRequest>,
+    pool: web::Data<sqlx::PgPool>,
+    keys: web::Data<JwtKeys>,
+) -> impl Responder {
+    match rotate_refresh_token(&pool, &keys, &refresh_request.refresh_token).await {
+        Ok(auth_data) => json_response(ApiResponse::success(auth_data)),
+        Err(RefreshError::Rejected(msg)) => {
+            json_response(ApiResponse::<MessageData>::error(401, msg.to_string()))
+        }
+        Err(RefreshError::Failed(msg)) => {
+            json_response(ApiResponse::<MessageData>::error(500, msg.to_string()))
+        }
+    }
+}
+
+enum RefreshError {
+    // The token can't be exchanged, the client has to sign in again
+    Rejected(&'static str),
+    Failed(&'static str),
+}
+
+fn refresh_failed(_: sqlx::Error) -> RefreshError {
+    RefreshError::Failed("Failed to refresh token")
+}
+
+// Uses up the refresh token for a new token pair in the same family. A token that was
+// used before was copied by someone, so its whole family is revoked instead
+async fn rotate_refresh_token(
+    pool: &sqlx::PgPool,
+    keys: &JwtKeys,
+    token: &str,
+) -> Result<AuthData, RefreshError> {
+    let mut tx = pool.begin().await.map_err(refresh_failed)?;
+
+    // Lock the token so it is exchanged once
+    let stored = sqlx::query!(
+        r#"
+        SELECT id, user_id, family_id, used_at IS NOT NULL as "used!",
+            revoked_at IS NOT NULL OR expires_at <= NOW() as "expired!"
+        FROM refresh_tokens
+        WHERE token_hash = $1
+        FOR UPDATE
+        "#,
+        auth::hash_secret(token)
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(refresh_failed)?
+    .ok_or(RefreshError::Rejected("Invalid refresh token"))?;
+
+    if stored.expired {
+        return Err(RefreshError::Rejected("Invalid refresh token"));
+    }
+    if stored.used {
+        auth::revoke_families(&mut tx, stored.user_id, Some(stored.family_id))
+            .await
+            .map_err(refresh_failed)?;
+        tx.commit().await.map_err(refresh_failed)?;
+        return Err(RefreshError::Rejected(
+            "Refresh token was already used, sign in again",
+        ));
+    }
+
+    sqlx::query!(
+        "UPDATE refresh_tokens SET used_at = CURRENT_TIMESTAMP WHERE id = $1",
+        stored.id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(refresh_failed)?;
+
+    let auth_data = auth::issue_tokens(&mut tx, keys, stored.user_id, stored.family_id)
+        .await
+        .map_err(RefreshError::Failed)?;
+
+    tx.commit().await.map_err(refresh_failed)?;
+    Ok(auth_data)
+}
+
+#[post("/user/logout")]
+pub async fn logout(
+    claims: Authenticated,
+    logout_request: Option<web::Json<LogoutRequest>>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let everywhere = logout_request.is_some_and(|logout_request| logout_request.all);
+
+    match end_sessions(&pool, &claims, everywhere).await {
+        Ok(()) => json_response(ApiResponse::success(MessageData {
+            message: "Logged out".to_string(),
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to log out".to_string(),
+        )),
+    }
+}
+
+// Revokes the access token and the session it came from, or every session of the user
+async fn end_sessions(
+    pool: &sqlx::PgPool,
+    claims: &auth::Claims,
+    everywhere: bool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    auth::revoke_token(&mut tx, claims).await?;
+    if everywhere {
+        auth::revoke_families(&mut tx, claims.sub, None).await?;
+    } else {
+        let family = sqlx::query!(
+            "SELECT family_id FROM refresh_tokens WHERE access_jti = $1",
+            claims.jti
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(family) = family {
+            auth::revoke_families(&mut tx, claims.sub, Some(family.family_id)).await?;
+        }
+    }
+
+    tx.commit().await
+}
+
+#[get("/user")]
+pub async fn get_user(claims: Authenticated, pool: web::Data<sqlx::PgPool>) -> impl Responder {
+    let user = sqlx::query!(
+        r#"SELECT email, role::text as "role!" FROM users WHERE id = $1"#,
+        claims.sub
+    )
+    .fetch_optional(&**pool)
+    .await;
+
+    let user = match user {
+        Ok(Some(user)) => user,
+        _ => {
+            return json_response(ApiResponse::<MessageData>::error(
+                404,
+                "User not found".to_string(),
+            ))
+        }
+    };
+
+    let balances = sqlx::query_as!(
+        BalanceResponse,
+        r#"
+        SELECT currency, balance, balance - held as "available_balance!"
+        FROM wallets WHERE user_id = $1 ORDER BY currency
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match balances {
+        Ok(balances) => json_response(ApiResponse::success(UserData {
+            email: user.email,
+            role: user.role,
+            balances,
+        })),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch balance".to_string(),
+        )),
+    }
+}
+
+// Changes what a user may do. Their sessions are ended, the next sign in issues tokens
+// with the new role
+#[post("/admin/users/role")]
+pub async fn set_user_role(
+    _admin: Authenticated<AdminRole>,
+    role_request: web::Json<SetRoleRequest>,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    let Some(role) = Role::parse(&role_request.role) else {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "role must be USER, MERCHANT, SUPPORT or ADMIN".to_string(),
+        ));
+    };
+
+    match change_role(&pool, &role_request.email, role).await {
+        Ok(true) => json_response(ApiResponse::success(SetRoleResponse {
+            email: role_request.email.clone(),
+            role,
+        })),
+        Ok(false) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "User not found".to_string(),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update role".to_string(),
+        )),
+    }
+}
+
+async fn change_role(pool: &sqlx::PgPool, email: &str, role: Role) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let Some(user) = sqlx::query!(
+        r#"
+        UPDATE users SET role = $2::text::user_role, updated_at = CURRENT_TIMESTAMP
+        WHERE email = $1
+        RETURNING id
+        "#,
+        email,
+        role.as_str()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    auth::revoke_families(&mut tx, user.id, None).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,571 @@
+use crate::connectors::{BankConnector, SettlementOutcome, Submission, WithdrawalOrder};
+use crate::engine::fees::{charge_fee, refund_fee, transfer_fee, FEE_ACCOUNT_ID};
+use crate::engine::limits::{exceeded_limit, limit_message};
+use crate::engine::lock_wallets;
+use crate::routes::scheduled::poll_interval;
+use crate::utils::{
+    auth::Authenticated,
+    currency::parse_currency,
+    idempotency::{self, Idempotency},
+    response::{json_response, ApiResponse, MessageData},
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize)]
+pub struct WithdrawalRequest {
+    amount: Decimal,
+    currency: Option<String>,
+    // Account number or IBAN the connector pays out to
+    bank_account: String,
+}
+
+#[derive(Serialize)]
+pub struct WithdrawalResponse {
+    id: Uuid,
+    amount: Decimal,
+    // Charged on top of the amount, refunded with it when the withdrawal fails
+    fee: Decimal,
+    currency: String,
+    bank_account: String,
+    status: String,
+    connector_reference: Option<String>,
+    failure_reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+}
+
+async fn find_withdrawal(
+    pool: &sqlx::PgPool,
+    withdrawal_id: Uuid,
+) -> Result<WithdrawalResponse, sqlx::Error> {
+    sqlx::query_as!(
+        WithdrawalResponse,
+        r#"
+        SELECT id, amount, fee, currency, bank_account, status::text as "status!",
+            connector_reference, failure_reason, created_at, updated_at
+        FROM withdrawals
+        WHERE id = $1
+        "#,
+        withdrawal_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+fn withdrawal_response(result: Result<WithdrawalResponse, sqlx::Error>) -> HttpResponse {
+    match result {
+        Ok(withdrawal) => json_response(ApiResponse::success(withdrawal)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch withdrawal".to_string(),
+        )),
+    }
+}
+
+#[post("/balance/withdraw")]
+pub async fn create_withdrawal(
+    claims: Authenticated,
+    req: actix_web::HttpRequest,
+    withdrawal_request: web::Json<WithdrawalRequest>,
+    pool: web::Data<sqlx::PgPool>,
+    connector: web::Data<dyn BankConnector>,
+) -> impl Responder {
+    // Replay the original response if this request was already handled
+    let idempotency_key =
+        match idempotency::begin(&req, &pool, claims.sub, &*withdrawal_request).await {
+            Idempotency::Proceed(key) => key,
+            Idempotency::Done(response) => return response,
+        };
+
+    let response = withdraw(&pool, &**connector, claims.sub, &withdrawal_request).await;
+
+    idempotency::finish(&pool, idempotency_key, response).await
+}
+
+// Takes the funds out of the wallet, then hands the withdrawal to the connector
+async fn withdraw(
+    pool: &sqlx::PgPool,
+    connector: &dyn BankConnector,
+    user_id: Uuid,
+    withdrawal_request: &WithdrawalRequest,
+) -> HttpResponse {
+    // Validate amount is positive
+    if withdrawal_request.amount <= Decimal::new(0, 0) {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "Amount must be positive".to_string(),
+        ));
+    }
+
+    let currency = match parse_currency(withdrawal_request.currency.as_deref()) {
+        Ok(currency) => currency,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(400, msg.to_string())),
+    };
+    let bank_account = withdrawal_request.bank_account.trim();
+    if bank_account.is_empty() {
+        return json_response(ApiResponse::<MessageData>::error(
+            400,
+            "bank_account is required".to_string(),
+        ));
+    }
+
+    // Start a transaction
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to start transaction".to_string(),
+            ))
+        }
+    };
+
+    let fee = match transfer_fee(
+        &mut tx,
+        user_id,
+        "WITHDRAWAL",
+        &currency,
+        withdrawal_request.amount,
+    )
+    .await
+    {
+        Ok(fee) => fee,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    };
+
+    let mut wallets = vec![(user_id, currency.clone())];
+    if fee > Decimal::new(0, 0) {
+        wallets.push((FEE_ACCOUNT_ID, currency.clone()));
+    }
+    if lock_wallets(&mut tx, &wallets).await.is_err() {
+        return json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        ));
+    }
+
+    // Withdrawals count towards the same limits as transfers, checked under the wallet lock
+    match exceeded_limit(&mut tx, user_id, &currency, withdrawal_request.amount).await {
+        Ok(None) => {}
+        Ok(Some(limit)) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                403,
+                limit_message(limit).to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Database error".to_string(),
+            ))
+        }
+    }
+
+    // Only the available balance can be withdrawn, the debit fails otherwise
+    let updated_wallet = sqlx::query!(
+        r#"
+        UPDATE wallets SET balance = balance - $1, updated_at = CURRENT_TIMESTAMP
+        WHERE user_id = $2 AND currency = $3 AND balance - held >= $1
+        RETURNING balance
+        "#,
+        withdrawal_request.amount + fee,
+        user_id,
+        currency
+    )
+    .fetch_optional(&mut *tx)
+    .await;
+
+    let balance = match updated_wallet {
+        Ok(Some(wallet)) => wallet.balance,
+        Ok(None) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                400,
+                "Insufficient balance".to_string(),
+            ))
+        }
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to update balance".to_string(),
+            ))
+        }
+    };
+
+    // The ledger entry shares its transfer_id with the withdrawal. Until the bank answers,
+    // the withdrawal is submitted again once retry_at passes
+    let withdrawal_id = Uuid::new_v4();
+    let created = sqlx::query_as!(
+        WithdrawalResponse,
+        r#"
+        WITH entry AS (
+            INSERT INTO transactions
+                (id, transfer_id, user_id, transaction_type, amount, currency, status,
+                 balance_after)
+            VALUES ($6, $1, $2, 'WITHDRAWAL', $3, $4, 'SUCCESS', $7)
+        )
+        INSERT INTO withdrawals (id, user_id, amount, fee, currency, bank_account, retry_at)
+        VALUES ($1, $2, $3, $8, $4, $5, NOW() + make_interval(secs => $9))
+        RETURNING id, amount, fee, currency, bank_account, status::text as "status!",
+            connector_reference, failure_reason, created_at, updated_at
+        "#,
+        withdrawal_id,
+        user_id,
+        withdrawal_request.amount,
+        currency,
+        bank_account,
+        Uuid::new_v4(),
+        // The fee entry comes right after and takes the balance the rest of the way
+        balance + fee,
+        fee,
+        poll_interval().as_secs_f64()
+    )
+    .fetch_one(&mut *tx)
+    .await;
+
+    let created = match created {
+        Ok(created) => created,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to create withdrawal".to_string(),
+            ))
+        }
+    };
+
+    if fee > Decimal::new(0, 0)
+        && charge_fee(&mut tx, withdrawal_id, user_id, &currency, fee, balance)
+            .await
+            .is_err()
+    {
+        return json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to charge fee".to_string(),
+        ));
+    }
+
+    // Commit the transaction
+    if tx.commit().await.is_err() {
+        return json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to commit transaction".to_string(),
+        ));
+    }
+
+    // The funds are taken, so from here on the withdrawal is reported as created. A server
+    // error would let a retry with the same Idempotency-Key withdraw again
+    let order = WithdrawalOrder {
+        id: withdrawal_id,
+        amount: withdrawal_request.amount,
+        currency,
+        bank_account: bank_account.to_string(),
+    };
+    if let Err(e) = submit_withdrawal(pool, connector, &order).await {
+        eprintln!("Failed to submit withdrawal {}: {}", withdrawal_id, e);
+    }
+
+    match find_withdrawal(pool, withdrawal_id).await {
+        Ok(withdrawal) => withdrawal_created(withdrawal),
+        // The withdrawal as it was committed
+        Err(_) => withdrawal_created(created),
+    }
+}
+
+// 202 while the bank hasn't answered yet, the withdrawal is submitted again later
+fn withdrawal_created(withdrawal: WithdrawalResponse) -> HttpResponse {
+    let status_code = if withdrawal.status == "PENDING" && withdrawal.connector_reference.is_none()
+    {
+        202
+    } else {
+        200
+    };
+    json_response(ApiResponse {
+        status_code,
+        ..ApiResponse::success(withdrawal)
+    })
+}
+
+// Hands a withdrawal to the bank and records its answer. Without one the withdrawal stays
+// due to be submitted again
+async fn submit_withdrawal(
+    pool: &sqlx::PgPool,
+    connector: &dyn BankConnector,
+    order: &WithdrawalOrder,
+) -> Result<(), String> {
+    match connector.submit(order).await? {
+        Submission::Accepted(reference) => sqlx::query!(
+            r#"
+            UPDATE withdrawals
+            SET connector_reference = $2, retry_at = NULL, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+            order.id,
+            reference
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string()),
+        // Rejected right away, the funds go straight back
+        Submission::Rejected(reason) => {
+            finish_withdrawal(pool, order.id, &SettlementOutcome::Failed(reason))
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Submits withdrawals the bank hasn't answered for again, returns how many were due
+pub async fn resubmit_withdrawals(
+    pool: &sqlx::PgPool,
+    connector: &dyn BankConnector,
+) -> Result<u64, sqlx::Error> {
+    // Claimed by pushing retry_at back, twice as far each time, so they aren't picked up
+    // again while the bank is being asked
+    let due = sqlx::query!(
+        r#"
+        UPDATE withdrawals
+        SET attempts = attempts + 1,
+            retry_at = NOW() + make_interval(secs => $1::float8 * power(2, LEAST(attempts, 6)))
+        WHERE id IN (
+            SELECT id FROM withdrawals
+            WHERE status = 'PENDING' AND retry_at <= NOW()
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, amount, currency, bank_account
+        "#,
+        poll_interval().as_secs_f64()
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for withdrawal in &due {
+        let order = WithdrawalOrder {
+            id: withdrawal.id,
+            amount: withdrawal.amount,
+            currency: withdrawal.currency.clone(),
+            bank_account: withdrawal.bank_account.clone(),
+        };
+        if let Err(e) = submit_withdrawal(pool, connector, &order).await {
+            eprintln!("Failed to submit withdrawal {}: {}", withdrawal.id, e);
+        }
+    }
+    Ok(due.len() as u64)
+}
+
+pub async fn process_withdrawal_submissions(connector: Arc<dyn BankConnector>) {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    loop {
+        if let Err(e) = resubmit_withdrawals(&pool, &*connector).await {
+            eprintln!("Failed to resubmit withdrawals: {}", e);
+        }
+        actix_rt::time::sleep(poll_interval()).await;
+    }
+}
+
+#[get("/balance/withdrawals")]
+pub async fn get_withdrawals(
+    claims: Authenticated,
+    pool: web::Data<sqlx::PgPool>,
+) -> impl Responder {
+    // Newest first
+    let withdrawals = sqlx::query_as!(
+        WithdrawalResponse,
+        r#"
+        SELECT id, amount, fee, currency, bank_account, status::text as "status!",
+            connector_reference, failure_reason, created_at, updated_at
+        FROM withdrawals
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+        claims.sub
+    )
+    .fetch_all(&**pool)
+    .await;
+
+    match withdrawals {
+        Ok(withdrawals) => json_response(ApiResponse::success(withdrawals)),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to fetch withdrawals".to_string(),
+        )),
+    }
+}
+
+// Called by the bank once a payout settles. There is no user token, the connector checks
+// the callback's signature instead
+#[post("/balance/withdraw/callback")]
+pub async fn withdrawal_callback(
+    req: actix_web::HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<sqlx::PgPool>,
+    connector: web::Data<dyn BankConnector>,
+) -> impl Responder {
+    let callback = match connector.parse_callback(&req, &body) {
+        Ok(callback) => callback,
+        Err(msg) => return json_response(ApiResponse::<MessageData>::error(401, msg.to_string())),
+    };
+    let final_status = match callback.outcome {
+        SettlementOutcome::Completed => "SUCCESS",
+        SettlementOutcome::Failed(_) => "FAILURE",
+    };
+
+    match finish_withdrawal(&pool, callback.withdrawal_id, &callback.outcome).await {
+        Ok(None) => json_response(ApiResponse::<MessageData>::error(
+            404,
+            "Withdrawal not found".to_string(),
+        )),
+        // Banks retry callbacks, repeating the same outcome is fine
+        Ok(Some(status)) if status == "PENDING" || status == final_status => {
+            withdrawal_response(find_withdrawal(&pool, callback.withdrawal_id).await)
+        }
+        Ok(Some(status)) => json_response(ApiResponse::<MessageData>::error(
+            409,
+            format!("Withdrawal is {}", status),
+        )),
+        Err(_) => json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to update withdrawal".to_string(),
+        )),
+    }
+}
+
+// Moves a PENDING withdrawal to its final status, a failed one is paid back into the
+// wallet along with its fee. Returns the status the withdrawal had before, None if it doesn't exist
+async fn finish_withdrawal(
+    pool: &sqlx::PgPool,
+    withdrawal_id: Uuid,
+    outcome: &SettlementOutcome,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Lock the withdrawal so it is finished once
+    let Some(withdrawal) = sqlx::query!(
+        r#"
+        SELECT user_id, amount, fee, currency, status::text as "status!"
+        FROM withdrawals
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        withdrawal_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    if withdrawal.status != "PENDING" {
+        return Ok(Some(withdrawal.status));
+    }
+
+    match outcome {
+        SettlementOutcome::Completed => {
+            sqlx::query!(
+                r#"
+                UPDATE withdrawals
+                SET status = 'SUCCESS', retry_at = NULL, updated_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                withdrawal_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        SettlementOutcome::Failed(reason) => {
+            sqlx::query!(
+                r#"
+                UPDATE withdrawals
+                SET status = 'FAILURE', failure_reason = $2, retry_at = NULL,
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                "#,
+                withdrawal_id,
+                reason
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let mut wallets = vec![(withdrawal.user_id, withdrawal.currency.clone())];
+            if withdrawal.fee > Decimal::new(0, 0) {
+                wallets.push((FEE_ACCOUNT_ID, withdrawal.currency.clone()));
+            }
+            lock_wallets(&mut tx, &wallets).await?;
+            let updated_wallet = sqlx::query!(
+                r#"
+                UPDATE wallets SET balance = balance + $1, updated_at = CURRENT_TIMESTAMP
+                WHERE user_id = $2 AND currency = $3
+                RETURNING balance
+                "#,
+                withdrawal.amount,
+                withdrawal.user_id,
+                withdrawal.currency
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            // Like a refunded transfer, the original entry is reversed and a
+            // compensating entry puts the money back
+            sqlx::query!(
+                r#"
+                UPDATE transactions SET status = 'REVERSED', updated_at = CURRENT_TIMESTAMP
+                WHERE transfer_id = $1 AND transaction_type = 'WITHDRAWAL'
+                "#,
+                withdrawal_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                r#"
+                INSERT INTO transactions
+                    (id, transfer_id, user_id, transaction_type, amount, currency, status,
+                     balance_after)
+                VALUES ($1, $2, $3, 'WITHDRAWAL_REFUND', $4, $5, 'SUCCESS', $6)
+                "#,
+                Uuid::new_v4(),
+                withdrawal_id,
+                withdrawal.user_id,
+                withdrawal.amount,
+                withdrawal.currency,
+                updated_wallet.balance
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            // The bank never paid out, so there is nothing the fee was for
+            if withdrawal.fee > Decimal::new(0, 0) {
+                refund_fee(
+                    &mut tx,
+                    withdrawal_id,
+                    withdrawal.user_id,
+                    &withdrawal.currency,
+                    withdrawal.fee,
+                )
+                .await?;
+            }
+        }
+    }
+
+    tx.commit().await?;
+    Ok(Some(withdrawal.status))
+}
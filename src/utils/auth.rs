@@ -1,60 +1,502 @@
-use actix_web::HttpRequest;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
-use std::env;
-use uuid::Uuid;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: Uuid,
-    pub exp: usize,
-}
-
-#[derive(Serialize)]
-pub struct AuthData {
-    pub token: String,
-}
-
-pub fn create_token(user_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let claims = Claims {
-        sub: user_id,
-        exp: (chrono::Utc::now() + chrono::Duration::hours(24)).timestamp() as usize,
-    };
-
-    let jwt_secret_key = env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY must be set");
-
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret_key.as_ref()),
-    )
-}
-
-pub fn verify_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let jwt_secret_key = env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY must be set");
-
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(jwt_secret_key.as_ref()),
-        &Validation::default(),
-    )?;
-
-    Ok(token_data.claims)
-}
-
-pub fn extract_token(auth_header: Option<&actix_web::http::header::HeaderValue>) -> Option<String> {
-    auth_header?
-        .to_str()
-        .ok()?
-        .strip_prefix("Bearer ")
-        .map(String::from)
-}
-
-pub fn verify_request_token(req: &HttpRequest) -> Result<Claims, &'static str> {
-    // Extract bearer token
-    let auth_header = req.headers().get("Authorization");
-    let token = extract_token(auth_header).ok_or("Invalid authorization header")?;
-
-    // Verify JWT
-    verify_token(&token).map_err(|_| "Invalid token")
-}
+use crate::utils::keys::JwtKeys;
+use crate::utils::response::{json_response, ApiResponse, MessageData};
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::{env, fmt};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Role {
+    #[default]
+    User,
+    Merchant,
+    Support,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role.trim().to_uppercase().as_str() {
+            "USER" => Some(Self::User),
+            "MERCHANT" => Some(Self::Merchant),
+            "SUPPORT" => Some(Self::Support),
+            "ADMIN" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "USER",
+            Self::Merchant => "MERCHANT",
+            Self::Support => "SUPPORT",
+            Self::Admin => "ADMIN",
+        }
+    }
+
+    // Every role can use what a user can, admins can use everything
+    pub fn grants(self, required: Role) -> bool {
+        self == required || required == Self::User || self == Self::Admin
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    // Identifies the token so it can be revoked
+    pub jti: Uuid,
+    pub exp: usize,
+    // The user's role when the token was issued, tokens from before roles count as users
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct AuthData {
+    // Short-lived access token
+    pub token: String,
+    // Exchanged for a new token pair at POST /user/token/refresh, works once
+    pub refresh_token: String,
+    // Seconds until the access token expires
+    pub expires_in: i64,
+}
+
+// How long an access token lasts, configurable through ACCESS_TOKEN_TTL_MINUTES
+fn access_token_ttl_minutes() -> i64 {
+    env::var("ACCESS_TOKEN_TTL_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(15)
+}
+
+// How long a refresh token lasts, configurable through REFRESH_TOKEN_TTL_DAYS
+fn refresh_token_ttl_days() -> i64 {
+    env::var("REFRESH_TOKEN_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30)
+}
+
+pub fn create_token(
+    keys: &JwtKeys,
+    user_id: Uuid,
+    role: Role,
+) -> Result<(String, Claims), jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: user_id,
+        jti: Uuid::new_v4(),
+        exp: (chrono::Utc::now() + chrono::Duration::minutes(access_token_ttl_minutes()))
+            .timestamp() as usize,
+        role,
+    };
+
+    let token = keys.sign(&claims)?;
+    Ok((token, claims))
+}
+
+fn expires_at(claims: &Claims) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(claims.exp as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+// Only the hash of a refresh token or API key is stored, a leaked table can't be used
+// to sign in
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+// 32 random bytes as hex
+fn random_secret() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Issues an access token and a refresh token in `family_id`, a new family for a login.
+// The access token carries the user's current role
+pub async fn issue_tokens(
+    conn: &mut sqlx::PgConnection,
+    keys: &JwtKeys,
+    user_id: Uuid,
+    family_id: Uuid,
+) -> Result<AuthData, &'static str> {
+    let role = sqlx::query!(
+        r#"SELECT role::text as "role!" FROM users WHERE id = $1"#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| "Failed to create token")?
+    .role;
+    let role = Role::parse(&role).ok_or("Failed to create token")?;
+
+    let (token, claims) =
+        create_token(keys, user_id, role).map_err(|_| "Failed to create token")?;
+    let refresh_token = random_secret();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens
+            (id, user_id, family_id, token_hash, access_jti, access_expires_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW() + make_interval(days => $7))
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        family_id,
+        hash_secret(&refresh_token),
+        claims.jti,
+        expires_at(&claims),
+        refresh_token_ttl_days() as i32
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|_| "Failed to create token")?;
+
+    Ok(AuthData {
+        token,
+        refresh_token,
+        expires_in: claims.exp as i64 - chrono::Utc::now().timestamp(),
+    })
+}
+
+// Revokes the refresh token families matching `family_id`, or every family of `user_id`
+// without one, along with the access tokens they issued that are still live
+pub async fn revoke_families(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+    family_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        WITH revoked AS (
+            UPDATE refresh_tokens SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP)
+            WHERE user_id = $1 AND ($2::uuid IS NULL OR family_id = $2)
+            RETURNING access_jti, access_expires_at
+        )
+        INSERT INTO revoked_tokens (jti, expires_at)
+        SELECT access_jti, access_expires_at FROM revoked WHERE access_expires_at > NOW()
+        ON CONFLICT (jti) DO NOTHING
+        "#,
+        user_id,
+        family_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Revokes a single access token until it expires
+pub async fn revoke_token(
+    conn: &mut sqlx::PgConnection,
+    claims: &Claims,
+) -> Result<(), sqlx::Error> {
+    // Expired entries are of no use anymore
+    sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at <= NOW()")
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)
+        ON CONFLICT (jti) DO NOTHING
+        "#,
+        claims.jti,
+        expires_at(claims)
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+pub fn verify_token(keys: &JwtKeys, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    keys.verify(token)
+}
+
+pub fn extract_token(auth_header: Option<&actix_web::http::header::HeaderValue>) -> Option<String> {
+    auth_header?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(String::from)
+}
+
+pub async fn verify_request_token(
+    req: &HttpRequest,
+    pool: &sqlx::PgPool,
+) -> Result<Claims, &'static str> {
+    // Extract bearer token
+    let auth_header = req.headers().get("Authorization");
+    let token = extract_token(auth_header).ok_or("Invalid authorization header")?;
+
+    // Verify JWT
+    let keys = req
+        .app_data::<web::Data<JwtKeys>>()
+        .ok_or("Token verification is not configured")?;
+    let claims = verify_token(keys, &token).map_err(|_| "Invalid token")?;
+
+    // A signed token still fails once it was revoked
+    let revoked = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1) as "revoked!""#,
+        claims.jti
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|_| "Failed to verify token")?
+    .revoked;
+    if revoked {
+        return Err("Token has been revoked");
+    }
+
+    Ok(claims)
+}
+
+fn request_pool(req: &HttpRequest) -> Result<&web::Data<sqlx::PgPool>, AuthError> {
+    req.app_data::<web::Data<sqlx::PgPool>>()
+        .ok_or(AuthError::new(500, "Database error"))
+}
+
+fn role_required(role: Role) -> &'static str {
+    match role {
+        Role::Admin => "Admin access required",
+        Role::Support => "Support access required",
+        Role::Merchant => "Merchant access required",
+        Role::User => "User access required",
+    }
+}
+
+// Marks the role an endpoint requires from its caller
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+pub struct UserRole;
+pub struct MerchantRole;
+pub struct SupportRole;
+pub struct AdminRole;
+
+impl RequiredRole for UserRole {
+    const ROLE: Role = Role::User;
+}
+
+impl RequiredRole for MerchantRole {
+    const ROLE: Role = Role::Merchant;
+}
+
+impl RequiredRole for SupportRole {
+    const ROLE: Role = Role::Support;
+}
+
+impl RequiredRole for AdminRole {
+    const ROLE: Role = Role::Admin;
+}
+
+// Rejects a request the same way the handlers reject one
+#[derive(Debug)]
+pub struct AuthError {
+    status_code: u16,
+    message: &'static str,
+}
+
+impl AuthError {
+    fn new(status_code: u16, message: &'static str) -> Self {
+        Self {
+            status_code,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message)
+    }
+}
+
+impl ResponseError for AuthError {
+    fn error_response(&self) -> HttpResponse {
+        json_response(ApiResponse::<MessageData>::error(
+            self.status_code,
+            self.message.to_string(),
+        ))
+    }
+}
+
+// The caller of an endpoint, from a valid bearer token whose role grants `R`. A handler
+// declares the role it requires by taking `Authenticated<AdminRole>` and so on, plain
+// `Authenticated` lets in any signed in user. Derefs to the token's claims
+pub struct Authenticated<R: RequiredRole = UserRole> {
+    pub claims: Claims,
+    role: PhantomData<R>,
+}
+
+impl<R: RequiredRole> Deref for Authenticated<R> {
+    type Target = Claims;
+
+    fn deref(&self) -> &Claims {
+        &self.claims
+    }
+}
+
+impl<R: RequiredRole> FromRequest for Authenticated<R> {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            // Only endpoints taking `Scoped` accept API keys
+            if req.headers().contains_key(API_KEY_HEADER) {
+                return Err(AuthError::new(
+                    403,
+                    "API keys can't be used for this endpoint",
+                ));
+            }
+
+            let pool = request_pool(&req)?;
+            let claims = verify_request_token(&req, pool)
+                .await
+                .map_err(|message| AuthError::new(401, message))?;
+
+            if !claims.role.grants(R::ROLE) {
+                return Err(AuthError::new(403, role_required(R::ROLE)));
+            }
+
+            Ok(Self {
+                claims,
+                role: PhantomData,
+            })
+        })
+    }
+}
+
+// Header API keys are sent in, instead of an Authorization bearer token
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+// What an API key can be allowed to do
+pub const API_KEY_SCOPES: [&str; 4] = [
+    BalanceRead::SCOPE,
+    TransactionsRead::SCOPE,
+    PaymentsRead::SCOPE,
+    PaymentsWrite::SCOPE,
+];
+
+// Marks the scope an API key needs for an endpoint
+pub trait RequiredScope {
+    const SCOPE: &'static str;
+}
+
+pub struct BalanceRead;
+pub struct TransactionsRead;
+pub struct PaymentsRead;
+pub struct PaymentsWrite;
+
+impl RequiredScope for BalanceRead {
+    const SCOPE: &'static str = "balance:read";
+}
+
+impl RequiredScope for TransactionsRead {
+    const SCOPE: &'static str = "transactions:read";
+}
+
+impl RequiredScope for PaymentsRead {
+    const SCOPE: &'static str = "payments:read";
+}
+
+impl RequiredScope for PaymentsWrite {
+    const SCOPE: &'static str = "payments:write";
+}
+
+// A new API key and its prefix. The key is sk_<prefix>_<secret>, the prefix tells keys
+// apart without revealing them. 13 hex digits fill the 16 characters the column allows
+pub fn generate_api_key() -> (String, String) {
+    let prefix = format!("sk_{}", &random_secret()[..13]);
+    let key = format!("{}_{}", prefix, random_secret());
+    (prefix, key)
+}
+
+// The owner of a live API key with `scope`, recording that the key was used. Keys stop
+// working when their owner loses the merchant role
+async fn verify_api_key(
+    pool: &sqlx::PgPool,
+    key: &str,
+    scope: &'static str,
+) -> Result<Uuid, AuthError> {
+    let api_key = sqlx::query!(
+        r#"
+        SELECT k.id, k.user_id, k.scopes, u.role::text as "role!"
+        FROM api_keys k
+        JOIN users u ON u.id = k.user_id
+        WHERE k.secret_hash = $1 AND k.revoked_at IS NULL
+            AND (k.expires_at IS NULL OR k.expires_at > NOW())
+        "#,
+        hash_secret(key)
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|_| AuthError::new(500, "Failed to verify API key"))?
+    .ok_or(AuthError::new(401, "Invalid API key"))?;
+
+    if !Role::parse(&api_key.role).is_some_and(|role| role.grants(Role::Merchant)) {
+        return Err(AuthError::new(403, role_required(Role::Merchant)));
+    }
+    if !api_key.scopes.iter().any(|granted| granted == scope) {
+        return Err(AuthError::new(403, "API key lacks the required scope"));
+    }
+
+    // Only requests the key was let through for count as a use
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE id = $1",
+        api_key.id
+    )
+    .execute(pool)
+    .await
+    .map_err(|_| AuthError::new(500, "Failed to verify API key"))?;
+
+    Ok(api_key.user_id)
+}
+
+// The caller of an endpoint merchants can also reach from their backend, either a signed
+// in user or an API key granted the scope `S`. `sub` is the user acting
+pub struct Scoped<S: RequiredScope> {
+    pub sub: Uuid,
+    scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> FromRequest for Scoped<S> {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let pool = request_pool(&req)?;
+            let sub = match req.headers().get(API_KEY_HEADER) {
+                Some(key) => {
+                    let key = key
+                        .to_str()
+                        .map_err(|_| AuthError::new(401, "Invalid API key"))?;
+                    verify_api_key(pool, key, S::SCOPE).await?
+                }
+                None => {
+                    verify_request_token(&req, pool)
+                        .await
+                        .map_err(|message| AuthError::new(401, message))?
+                        .sub
+                }
+            };
+
+            Ok(Self {
+                sub,
+                scope: PhantomData,
+            })
+        })
+    }
+}
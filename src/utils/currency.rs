@@ -0,0 +1,19 @@
+use rust_decimal::Decimal;
+
+// Requests without a currency use the wallet in this currency
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+// Currencies are three letter ISO 4217 style codes, stored upper case
+pub fn parse_currency(code: Option<&str>) -> Result<String, &'static str> {
+    let code = code.unwrap_or(DEFAULT_CURRENCY);
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(code.to_ascii_uppercase())
+    } else {
+        Err("Invalid currency")
+    }
+}
+
+// Amount credited for `amount` at `rate`, in minor units of the target currency
+pub fn convert(amount: Decimal, rate: Decimal) -> Decimal {
+    (amount * rate).round_dp(2)
+}
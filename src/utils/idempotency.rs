@@ -0,0 +1,253 @@
+use crate::utils::response::{json_response, ApiResponse, MessageData};
+use actix_web::{body::to_bytes, http::StatusCode, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use uuid::Uuid;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// Set on responses that were replayed from a stored idempotency key
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+pub struct IdempotencyKey {
+    user_id: Uuid,
+    key: String,
+}
+
+pub enum Idempotency {
+    // No key was sent, or the key is new and has been reserved for this request
+    Proceed(Option<IdempotencyKey>),
+    // The key was seen before; respond with this instead of running the request
+    Done(HttpResponse),
+}
+
+// How long a key is remembered, configurable through IDEMPOTENCY_KEY_TTL_HOURS
+fn key_ttl_hours() -> i32 {
+    env::var("IDEMPOTENCY_KEY_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(24)
+}
+
+// How long a reserved key waits for its response before another request with the same key
+// and body may take it over, configurable through IDEMPOTENCY_KEY_LEASE_SECONDS. Covers a
+// process that died, or a response that couldn't be stored
+fn key_lease_seconds() -> f64 {
+    env::var("IDEMPOTENCY_KEY_LEASE_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(60.0)
+}
+
+pub fn fingerprint<T: Serialize>(req: &HttpRequest, body: &T) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(req.path());
+    hasher.update(serde_json::to_vec(body).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+// Reserve the request's Idempotency-Key, or return the response it already got
+pub async fn begin<T: Serialize>(
+    req: &HttpRequest,
+    pool: &sqlx::PgPool,
+    user_id: Uuid,
+    body: &T,
+) -> Idempotency {
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => return Idempotency::Proceed(None),
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= 255 => key.to_string(),
+            _ => {
+                return Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+                    400,
+                    "Invalid Idempotency-Key header".to_string(),
+                )))
+            }
+        },
+    };
+    let request_fingerprint = fingerprint(req, body);
+
+    // Expired keys can be reused
+    let expired = sqlx::query!(
+        "DELETE FROM idempotency_keys WHERE user_id = $1 AND expires_at <= NOW()",
+        user_id
+    )
+    .execute(pool)
+    .await;
+    if expired.is_err() {
+        return Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Failed to store idempotency key".to_string(),
+        )));
+    }
+
+    let reserved = sqlx::query!(
+        r#"
+        INSERT INTO idempotency_keys (user_id, idempotency_key, request_fingerprint, expires_at)
+        VALUES ($1, $2, $3, NOW() + make_interval(hours => $4))
+        ON CONFLICT (user_id, idempotency_key) DO NOTHING
+        "#,
+        user_id,
+        key,
+        request_fingerprint,
+        key_ttl_hours()
+    )
+    .execute(pool)
+    .await;
+
+    match reserved {
+        Ok(result) if result.rows_affected() == 1 => {
+            return Idempotency::Proceed(Some(IdempotencyKey { user_id, key }))
+        }
+        Ok(_) => {}
+        Err(_) => {
+            return Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to store idempotency key".to_string(),
+            )))
+        }
+    }
+
+    let existing = sqlx::query!(
+        r#"
+        SELECT request_fingerprint, response_status, response_body,
+            locked_at <= NOW() - make_interval(secs => $3) as "stale!"
+        FROM idempotency_keys
+        WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+        user_id,
+        key,
+        key_lease_seconds()
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match existing {
+        Ok(Some(record)) if record.request_fingerprint != request_fingerprint => {
+            Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+                409,
+                "Idempotency-Key was already used with a different request".to_string(),
+            )))
+        }
+        Ok(Some(record)) => match (record.response_status, record.response_body) {
+            (Some(status), Some(body)) => Idempotency::Done(
+                HttpResponse::build(
+                    StatusCode::from_u16(status as u16)
+                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                )
+                .insert_header((IDEMPOTENT_REPLAYED_HEADER, "true"))
+                .content_type("application/json")
+                .body(body),
+            ),
+            // Nothing answered within the lease, so this request takes the key over
+            _ if record.stale => take_over(pool, user_id, key).await,
+            _ => Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+                409,
+                "A request with this Idempotency-Key is still in progress".to_string(),
+            ))),
+        },
+        Ok(None) => Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+            409,
+            "A request with this Idempotency-Key is still in progress".to_string(),
+        ))),
+        Err(_) => Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        ))),
+    }
+}
+
+// Renews a stale reservation for this request, unless another request renewed it first
+async fn take_over(pool: &sqlx::PgPool, user_id: Uuid, key: String) -> Idempotency {
+    let renewed = sqlx::query!(
+        r#"
+        UPDATE idempotency_keys SET locked_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND idempotency_key = $2 AND response_status IS NULL
+            AND locked_at <= NOW() - make_interval(secs => $3)
+        "#,
+        user_id,
+        key,
+        key_lease_seconds()
+    )
+    .execute(pool)
+    .await;
+
+    match renewed {
+        Ok(result) if result.rows_affected() == 1 => {
+            Idempotency::Proceed(Some(IdempotencyKey { user_id, key }))
+        }
+        Ok(_) => Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+            409,
+            "A request with this Idempotency-Key is still in progress".to_string(),
+        ))),
+        Err(_) => Idempotency::Done(json_response(ApiResponse::<MessageData>::error(
+            500,
+            "Database error".to_string(),
+        ))),
+    }
+}
+
+// Store the response for a reserved key so retries get the same answer
+pub async fn finish(
+    pool: &sqlx::PgPool,
+    key: Option<IdempotencyKey>,
+    response: HttpResponse,
+) -> HttpResponse {
+    let Some(key) = key else {
+        return response;
+    };
+    let status = response.status();
+
+    // Server errors are not remembered, so the client can retry with the same key. A key
+    // that can't be released is taken over once its lease runs out
+    if status.is_server_error() {
+        if let Err(e) = sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE user_id = $1 AND idempotency_key = $2",
+            key.user_id,
+            key.key
+        )
+        .execute(pool)
+        .await
+        {
+            eprintln!("Failed to release Idempotency-Key {}: {}", key.key, e);
+        }
+        return response;
+    }
+
+    let body = match to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(_) => {
+            return json_response(ApiResponse::<MessageData>::error(
+                500,
+                "Failed to read response".to_string(),
+            ))
+        }
+    };
+
+    let stored = sqlx::query!(
+        r#"
+        UPDATE idempotency_keys
+        SET response_status = $3, response_body = $4
+        WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+        key.user_id,
+        key.key,
+        status.as_u16() as i32,
+        String::from_utf8_lossy(&body).to_string()
+    )
+    .execute(pool)
+    .await;
+    // The request went through either way, so its response is still returned
+    if let Err(e) = stored {
+        eprintln!(
+            "Failed to store the response for Idempotency-Key {}: {}",
+            key.key, e
+        );
+    }
+
+    HttpResponse::build(status)
+        .content_type("application/json")
+        .body(body)
+}
@@ -0,0 +1,173 @@
+use crate::utils::auth::Claims;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey};
+use jsonwebtoken::{errors::ErrorKind, Header, Validation};
+use rsa::pkcs8::{spki::SubjectPublicKeyInfoRef, DecodePublicKey, Document};
+use rsa::traits::PublicKeyParts;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{env, fs};
+
+const RSA_OID: &str = "1.2.840.113549.1.1.1";
+const ED25519_OID: &str = "1.3.101.112";
+
+// A key tokens can be verified with, published in the JWKS when it is public
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    jwk: Option<serde_json::Value>,
+}
+
+// The keys access tokens are signed and verified with, loaded once at startup
+pub struct JwtKeys {
+    signing_kid: Option<String>,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    // By the kid in the token header, keys rotated out stay until their tokens expired
+    verification_keys: HashMap<Option<String>, VerificationKey>,
+}
+
+impl JwtKeys {
+    // Key pairs from JWT_KEYS_DIR when it is set, signing with JWT_SIGNING_KEY_ID.
+    // Otherwise HS256 with JWT_SECRET_KEY, which other services can't verify on their own
+    pub fn from_env() -> Self {
+        match env::var("JWT_KEYS_DIR") {
+            Ok(dir) => {
+                let signing_kid =
+                    env::var("JWT_SIGNING_KEY_ID").expect("JWT_SIGNING_KEY_ID must be set");
+                Self::from_dir(Path::new(&dir), &signing_kid)
+                    .unwrap_or_else(|e| panic!("Failed to load JWT keys: {}", e))
+            }
+            Err(_) => {
+                Self::from_secret(&env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY must be set"))
+            }
+        }
+    }
+
+    pub fn from_secret(secret: &str) -> Self {
+        let verification_key = VerificationKey {
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+        };
+        Self {
+            signing_kid: None,
+            signing_algorithm: Algorithm::HS256,
+            signing_key: EncodingKey::from_secret(secret.as_bytes()),
+            verification_keys: HashMap::from([(None, verification_key)]),
+        }
+    }
+
+    // Every <kid>.pub.pem in `dir` verifies tokens, RSA keys as RS256 and Ed25519 keys as
+    // EdDSA. New tokens are signed with <signing_kid>.pem. Rotating is adding the next
+    // pair and switching the signing kid, then dropping the old .pem. The old .pub.pem
+    // can go once the tokens it signed expired
+    pub fn from_dir(dir: &Path, signing_kid: &str) -> Result<Self, String> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let mut verification_keys = HashMap::new();
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let Some(kid) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".pub.pem"))
+            else {
+                continue;
+            };
+            let pem = fs::read_to_string(&path).map_err(|e| format!("{}: {}", kid, e))?;
+            let key = verification_key(kid, &pem).map_err(|e| format!("{}: {}", kid, e))?;
+            verification_keys.insert(Some(kid.to_string()), key);
+        }
+
+        let signing_algorithm = verification_keys
+            .get(&Some(signing_kid.to_string()))
+            .map(|key| key.algorithm)
+            .ok_or_else(|| format!("{}: no public key to verify its tokens", signing_kid))?;
+        let pem = fs::read(dir.join(format!("{}.pem", signing_kid)))
+            .map_err(|e| format!("{}: {}", signing_kid, e))?;
+        let signing_key = match signing_algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+            _ => EncodingKey::from_rsa_pem(&pem),
+        }
+        .map_err(|e| format!("{}: {}", signing_kid, e))?;
+
+        Ok(Self {
+            signing_kid: Some(signing_kid.to_string()),
+            signing_algorithm,
+            signing_key,
+            verification_keys,
+        })
+    }
+
+    pub fn sign(&self, claims: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = self.signing_kid.clone();
+        encode(&header, claims, &self.signing_key)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        // The kid picks the key and the key picks the algorithm, never the header's alg
+        let header = decode_header(token)?;
+        let key = self
+            .verification_keys
+            .get(&header.kid)
+            .ok_or(ErrorKind::InvalidSignature)?;
+
+        let token_data = decode::<Claims>(token, &key.key, &Validation::new(key.algorithm))?;
+        Ok(token_data.claims)
+    }
+
+    // The public keys as a JWK Set, for services that verify tokens themselves
+    pub fn jwks(&self) -> serde_json::Value {
+        let mut keys: Vec<_> = self
+            .verification_keys
+            .values()
+            .filter_map(|key| key.jwk.clone())
+            .collect();
+        keys.sort_by(|a, b| a["kid"].as_str().cmp(&b["kid"].as_str()));
+        json!({ "keys": keys })
+    }
+}
+
+fn verification_key(kid: &str, pem: &str) -> Result<VerificationKey, String> {
+    let (_, document) = Document::from_pem(pem).map_err(|e| e.to_string())?;
+    let spki = SubjectPublicKeyInfoRef::try_from(document.as_bytes()).map_err(|e| e.to_string())?;
+
+    match spki.algorithm.oid.to_string().as_str() {
+        RSA_OID => {
+            let public_key = rsa::RsaPublicKey::from_public_key_der(document.as_bytes())
+                .map_err(|e| e.to_string())?;
+            let n = URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be());
+            let e = URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be());
+            Ok(VerificationKey {
+                algorithm: Algorithm::RS256,
+                key: DecodingKey::from_rsa_components(&n, &e).map_err(|e| e.to_string())?,
+                jwk: Some(json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": kid,
+                    "n": n,
+                    "e": e,
+                })),
+            })
+        }
+        ED25519_OID => {
+            let x = URL_SAFE_NO_PAD.encode(spki.subject_public_key.raw_bytes());
+            Ok(VerificationKey {
+                algorithm: Algorithm::EdDSA,
+                key: DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?,
+                jwk: Some(json!({
+                    "kty": "OKP",
+                    "use": "sig",
+                    "alg": "EdDSA",
+                    "crv": "Ed25519",
+                    "kid": kid,
+                    "x": x,
+                })),
+            })
+        }
+        oid => Err(format!("unsupported key algorithm {}", oid)),
+    }
+}
@@ -1,2 +1,5 @@
-pub mod auth;
-pub mod response;
+pub mod auth;
+pub mod currency;
+pub mod idempotency;
+pub mod keys;
+pub mod response;